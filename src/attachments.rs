@@ -0,0 +1,174 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use iced::widget::image::Handle;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use anyhow::{Result, bail};
+use crate::utils::estimate_tokens;
+
+const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+/// About 16k tokens, longer files are cut
+const MAX_FILE_CHARS: usize = 64_000;
+/// Bytes looked at to tell binary files apart
+const BINARY_PROBE: usize = 8192;
+pub const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+/// Image sent with a prompt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Image {
+    pub mime: String,
+    /// Base64 encoded content
+    pub data: String,
+}
+
+impl Image {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime, self.data)
+    }
+}
+
+/// Image attached to the query, with a handle to draw its thumbnail
+#[derive(Debug, Clone)]
+pub struct ImageAttachment {
+    pub name: String,
+    pub mime: String,
+    pub bytes: Vec<u8>,
+    pub handle: Handle,
+}
+
+impl ImageAttachment {
+    pub fn new(name: String, mime: &str, bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() > MAX_IMAGE_SIZE {
+            bail!("{} is too large: {} bytes, the limit is {}", name, bytes.len(), MAX_IMAGE_SIZE);
+        }
+        Ok(Self {
+            name,
+            mime: mime.to_string(),
+            handle: Handle::from_bytes(bytes.clone()),
+            bytes,
+        })
+    }
+
+    /// Attachment of an image sent before, to send it again
+    pub fn from_image(img: &Image) -> Result<Self> {
+        let bytes = STANDARD.decode(img.data.as_bytes())?;
+        let ext = img.mime.rsplit('/').next().unwrap_or_default();
+        Self::new(format!("image.{}", ext), img.mime.as_str(), bytes)
+    }
+
+    pub fn to_image(&self) -> Image {
+        Image { mime: self.mime.clone(), data: STANDARD.encode(&self.bytes) }
+    }
+}
+
+pub fn image_mime(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+pub async fn load_image(path: PathBuf) -> Result<ImageAttachment> {
+    let Some(mime) = image_mime(&path) else {
+        bail!("{} is not a supported image, use {}", path.display(), IMAGE_EXTENSIONS.join(", "));
+    };
+    let bytes = tokio::fs::read(&path).await?;
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    ImageAttachment::new(name, mime, bytes)
+}
+
+/// Reads the image in the clipboard and encodes it as PNG
+pub fn clipboard_image() -> Result<ImageAttachment> {
+    let img = arboard::Clipboard::new()?.get_image()?;
+    let mut bytes = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, img.width as u32, img.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&img.bytes)?;
+    }
+    ImageAttachment::new("clipboard.png".to_string(), "image/png", bytes)
+}
+
+/// Text file attached to the prompt, stored with the message it was sent with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAttachment {
+    pub name: String,
+    pub path: String,
+    pub encoding: String,
+    pub content: String,
+    pub tokens: u64,
+    /// The file was longer than the limit, only its beginning is kept
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+/// Decodes the text by its BOM, as UTF-8 or by the guessed encoding
+fn decode(bytes: &[u8]) -> Result<(String, String)> {
+    if let Some((enc, bom_len)) = encoding_rs::Encoding::for_bom(bytes) {
+        let (s, _) = enc.decode_without_bom_handling(&bytes[bom_len..]);
+        return Ok((s.into_owned(), enc.name().to_string()));
+    }
+    if bytes.iter().take(BINARY_PROBE).any(|b| *b == 0) {
+        bail!("It is not a text file");
+    }
+    if let Ok(s) = std::str::from_utf8(bytes) {
+        return Ok((s.to_string(), "UTF-8".to_string()));
+    }
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(bytes, true);
+    let enc = detector.guess(None, true);
+    let (s, _, _) = enc.decode(bytes);
+    Ok((s.into_owned(), enc.name().to_string()))
+}
+
+/// Cuts the text at the last line ending before the limit
+fn truncate(content: &mut String) -> bool {
+    let Some((cut, _)) = content.char_indices().nth(MAX_FILE_CHARS) else {
+        return false;
+    };
+    let cut = content[..cut].rfind('\n').map(|i| i + 1).unwrap_or(cut);
+    content.truncate(cut);
+    true
+}
+
+pub async fn load_file(path: PathBuf) -> Result<FileAttachment> {
+    let name = path.file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let size = tokio::fs::metadata(&path).await?.len();
+    if size > MAX_FILE_SIZE {
+        bail!("{} is too large: {} bytes, the limit is {}", name, size, MAX_FILE_SIZE);
+    }
+    let bytes = tokio::fs::read(&path).await?;
+    let (mut content, encoding) = decode(&bytes)
+        .map_err(|e| anyhow::anyhow!("Cannot attach {}: {}", name, e))?;
+    let truncated = truncate(&mut content);
+    Ok(FileAttachment {
+        name,
+        path: path.display().to_string(),
+        encoding,
+        tokens: estimate_tokens(content.as_str()),
+        content,
+        truncated,
+    })
+}
+
+/// Puts the files before the text of the prompt, each one between delimiters
+pub fn inject(text: &str, files: &[FileAttachment]) -> String {
+    let mut res = String::new();
+    for f in files {
+        let note = if f.truncated { " truncated=\"true\"" } else { "" };
+        res.push_str(format!("<file name=\"{}\"{}>\n{}\n</file>\n\n", f.name, note, f.content.trim_end()).as_str());
+    }
+    res.push_str(text);
+    res
+}
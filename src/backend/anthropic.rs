@@ -0,0 +1,322 @@
+use iced::futures::future::BoxFuture;
+use iced::futures::StreamExt;
+use openai::chat::ChatCompletionMessageRole;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::Sender;
+use anyhow::Result;
+use crate::config::AiApi;
+use crate::usage::Usage;
+use crate::tools::{ToolCall, ToolSpec};
+use crate::chat::{ChatError, ChatMessage};
+use super::{ChatBackend, ChatStream, Delta, Lines, retry_after};
+
+const API_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u64 = 4096;
+
+/// Anthropic's Messages API
+pub struct AnthropicBackend {
+    api: AiApi,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct MessagesRequest<'a> {
+    model: &'a str,
+    max_tokens: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<RequestMessage>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMessage {
+    role: &'static str,
+    content: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart { message: StartMessage },
+    ContentBlockStart { content_block: StartBlock },
+    ContentBlockDelta { delta: BlockDelta },
+    ContentBlockStop,
+    MessageDelta { usage: MessageUsage },
+    MessageStop,
+    Error { error: ApiError },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta { text: String },
+    ThinkingDelta { thinking: String },
+    InputJsonDelta { partial_json: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StartBlock {
+    ToolUse { id: String, name: String },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct StartMessage {
+    usage: MessageUsage,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiError {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorResponse {
+    error: ApiError,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+impl AnthropicBackend {
+    pub fn new(api: AiApi) -> Self {
+        Self { client: super::client(&api), api }
+    }
+
+    fn url(&self, path: &str) -> String {
+        let base = self.api.url.trim_end_matches('/');
+        let base = base.strip_suffix("/v1").unwrap_or(base);
+        format!("{}/v1/{}", base, path)
+    }
+
+    fn headers(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        req.header("x-api-key", self.api.key.as_str())
+            .header("anthropic-version", API_VERSION)
+    }
+}
+
+/// The system prompt is a separate field and the conversation has to alternate between the user and the assistant.
+/// Tool calls are content blocks of the assistant and their results blocks of the user.
+fn split_system(messages: &[ChatMessage]) -> (Option<String>, Vec<RequestMessage>) {
+    let mut system: Vec<&str> = vec![];
+    let mut res: Vec<RequestMessage> = vec![];
+    for m in messages {
+        let content = m.content.as_deref().unwrap_or_default();
+        let mut blocks = vec![];
+        let role = match m.role {
+            ChatCompletionMessageRole::System => {
+                system.push(content);
+                continue;
+            }
+            ChatCompletionMessageRole::Tool => {
+                blocks.push(json!({ "type": "tool_result", "tool_use_id": m.tool_call_id, "content": content }));
+                "user"
+            }
+            ChatCompletionMessageRole::Assistant => "assistant",
+            _ => "user",
+        };
+        for image in m.images.iter() {
+            blocks.push(json!({ "type": "image", "source": { "type": "base64", "media_type": image.mime, "data": image.data } }));
+        }
+        if m.role != ChatCompletionMessageRole::Tool && !content.is_empty() {
+            blocks.push(json!({ "type": "text", "text": content }));
+        }
+        for c in m.tool_calls.iter().flatten() {
+            let input: Value = serde_json::from_str(c.function.arguments.as_str()).unwrap_or(json!({}));
+            blocks.push(json!({ "type": "tool_use", "id": c.id, "name": c.function.name, "input": input }));
+        }
+        if blocks.is_empty() {
+            continue;
+        }
+        match res.last_mut() {
+            Some(last) if last.role == role => last.content.extend(blocks),
+            _ => res.push(RequestMessage { role, content: blocks }),
+        }
+    }
+    let system = if system.is_empty() { None } else { Some(system.join("\n\n")) };
+    (system, res)
+}
+
+/// Reads the error the API describes in the body of an unsuccessful response
+async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    let status = res.status();
+    let retry = retry_after(&res);
+    let body = res.text().await.unwrap_or_default();
+    let message = match serde_json::from_str::<ErrorResponse>(body.as_str()) {
+        Ok(e) => format!("{}: {}", e.error.kind, e.error.message),
+        Err(_) => body,
+    };
+    // Overloaded is a status of its own, 529
+    Err(ChatError::from_status(status, retry, message).into())
+}
+
+async fn forward_stream(res: reqwest::Response, tx: Sender<Delta>) -> Result<()> {
+    let mut lines = Lines::new(res.bytes_stream().boxed());
+    let mut usage = Usage::default();
+    let mut call: Option<ToolCall> = None;
+    while let Some(line) = lines.next().await? {
+        // The event name is repeated as the type in the data, so event: lines can be skipped
+        let Some(data) = line.strip_prefix("data:") else {
+            continue;
+        };
+        match serde_json::from_str::<StreamEvent>(data.trim())? {
+            StreamEvent::MessageStart { message } => {
+                usage.prompt_tokens = message.usage.input_tokens;
+            }
+            StreamEvent::MessageDelta { usage: u } => {
+                usage.completion_tokens = u.output_tokens;
+            }
+            StreamEvent::MessageStop => {
+                tx.send(Delta::Usage(usage)).await?;
+            }
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::TextDelta { text } } => {
+                tx.send(Delta::Content(text)).await?;
+            }
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::ThinkingDelta { thinking } } => {
+                tx.send(Delta::Reasoning(thinking)).await?;
+            }
+            StreamEvent::ContentBlockStart { content_block: StartBlock::ToolUse { id, name } } => {
+                call = Some(ToolCall { id, name, arguments: String::new() });
+            }
+            StreamEvent::ContentBlockDelta { delta: BlockDelta::InputJsonDelta { partial_json } } => {
+                if let Some(c) = call.as_mut() {
+                    c.arguments.push_str(partial_json.as_str());
+                }
+            }
+            StreamEvent::ContentBlockStop => {
+                if let Some(c) = call.take() {
+                    tx.send(Delta::ToolCall(c)).await?;
+                }
+            }
+            StreamEvent::Error { error } => {
+                anyhow::bail!("{}: {}", error.kind, error.message);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl ChatBackend for AnthropicBackend {
+    fn stream(&self, messages: &[ChatMessage], tools: &[ToolSpec]) -> BoxFuture<'_, Result<ChatStream>> {
+        let (system, messages) = split_system(messages);
+        // Penalties and seed are not supported by this API
+        let params = self.api.params.clone().unwrap_or_default();
+        let body = MessagesRequest {
+            model: self.api.model.as_str(),
+            max_tokens: params.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            system,
+            messages,
+            stream: true,
+            temperature: params.temperature,
+            top_p: params.top_p,
+            stop_sequences: params.stop,
+            tools: tools.iter()
+                .map(|t| json!({ "name": t.name, "description": t.description, "input_schema": t.parameters }))
+                .collect(),
+        };
+        let req = self.headers(self.client.post(self.url("messages"))).json(&body);
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            Ok(ChatStream::spawn(|tx| forward_stream(res, tx)))
+        })
+    }
+
+    fn models(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        let req = self.headers(self.client.get(self.url("models?limit=1000")));
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            let list: ModelList = res.json().await?;
+            let mut models: Vec<String> = list.data.into_iter().map(|m| m.id).collect();
+            models.sort();
+            Ok(models)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests::{forwarded, respond};
+
+    #[tokio::test]
+    async fn events_split_between_reads() {
+        let res = respond(&[
+            "event: message_start\ndata: ",
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":5}}}"#,
+            "\n\nevent: content_block_delta\n",
+            r#"data: {"type":"content_block_delta","index":0,"delta":{"type":"thinking_delta","thinking":"Hm"}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_delta","index":1,"delta":{"type":"text_delta","text":"Hel"#,
+            r#"lo"}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_start","index":2,"content_block":{"type":"tool_use","id":"t1","name":"clock"}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":"{\"tz\""}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_delta","index":2,"delta":{"type":"input_json_delta","partial_json":":\"UTC\"}"}}"#,
+            "\n\n",
+            r#"data: {"type":"content_block_stop","index":2}"#,
+            "\n\n",
+            r#"data: {"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":3}}"#,
+            "\n\n",
+            r#"data: {"type":"message_stop"}"#,
+            "\n\n",
+        ]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert_eq!(e, None);
+        assert_eq!(deltas, vec![
+            Delta::Reasoning("Hm".to_string()),
+            Delta::Content("Hello".to_string()),
+            Delta::ToolCall(ToolCall { id: "t1".to_string(), name: "clock".to_string(), arguments: r#"{"tz":"UTC"}"#.to_string() }),
+            Delta::Usage(Usage { prompt_tokens: 5, completion_tokens: 3 }),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn error_event_ends_the_stream() {
+        let res = respond(&[
+            r#"data: {"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+            "\n\n",
+        ]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert!(deltas.is_empty());
+        assert!(e.is_some_and(|e| e.is_transient()));
+    }
+}
@@ -0,0 +1,52 @@
+use iced::futures::future::BoxFuture;
+use openai::chat::ChatCompletionMessageRole;
+use anyhow::Result;
+use std::sync::atomic::{AtomicU32, Ordering};
+use crate::config::AiApi;
+use crate::tools::ToolSpec;
+use crate::chat::{ChatError, ChatMessage};
+use super::{ChatBackend, ChatStream, Delta};
+
+const WORD_DELAY_MS: u64 = 20;
+/// Model failing its first request like an overloaded provider, to try the retries
+pub const FLAKY_MODEL: &str = "flaky";
+
+/// Deterministic backend answering with the last prompt, for trying the app and the chat worker without a provider
+pub struct MockBackend {
+    api: AiApi,
+    requests: AtomicU32,
+}
+
+impl MockBackend {
+    pub fn new(api: AiApi) -> Self {
+        Self { api, requests: AtomicU32::new(0) }
+    }
+}
+
+impl ChatBackend for MockBackend {
+    fn stream(&self, messages: &[ChatMessage], _tools: &[ToolSpec]) -> BoxFuture<'_, Result<ChatStream>> {
+        let prompt = messages.iter()
+            .rev()
+            .find(|m| m.role == ChatCompletionMessageRole::User)
+            .and_then(|m| m.content.clone())
+            .unwrap_or_default();
+        let answer = format!("{} heard: {}", self.api.model, prompt);
+        let first = self.requests.fetch_add(1, Ordering::Relaxed) == 0;
+        if first && self.api.model == FLAKY_MODEL {
+            return Box::pin(async { Err(ChatError::Server("Overloaded".to_string()).into()) });
+        }
+        Box::pin(async move {
+            Ok(ChatStream::spawn(|tx| async move {
+                for word in answer.split_inclusive(' ') {
+                    tx.send(Delta::Content(word.to_string())).await?;
+                    tokio::time::sleep(tokio::time::Duration::from_millis(WORD_DELAY_MS)).await;
+                }
+                Ok(())
+            }))
+        })
+    }
+
+    fn models(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        Box::pin(async { Ok(vec!["mock".to_string()]) })
+    }
+}
@@ -0,0 +1,244 @@
+use iced::futures::future::BoxFuture;
+use iced::futures::{Stream, StreamExt};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::task::JoinHandle;
+use std::time::Duration;
+use tracing::{debug, error};
+use anyhow::Result;
+use crate::config::{AiApi, BackendKind};
+use crate::usage::Usage;
+use crate::tools::{ToolCall, ToolSpec};
+use crate::chat::{ChatError, ChatMessage};
+
+pub mod openai;
+pub mod ollama;
+pub mod anthropic;
+pub mod mock;
+
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A piece of the streamed answer
+#[derive(Debug, Clone, PartialEq)]
+pub enum Delta {
+    Content(String),
+    Reasoning(String),
+    /// Tokens used by the request, sent at the end when the provider reports them
+    Usage(Usage),
+    /// Complete call of a tool, sent once all of its arguments were received
+    ToolCall(ToolCall),
+    Error(ChatError),
+}
+
+/// Provider API able to stream chat completions
+pub trait ChatBackend: Send + Sync {
+    /// Starts a streamed completion of the conversation, the model may call the given tools
+    fn stream(&self, messages: &[ChatMessage], tools: &[ToolSpec]) -> BoxFuture<'_, Result<ChatStream>>;
+
+    /// Lists the models available with the configured key
+    fn models(&self) -> BoxFuture<'_, Result<Vec<String>>>;
+}
+
+/// HTTP client giving up when the provider stays silent for longer than the timeout of the chat
+pub fn client(api: &AiApi) -> reqwest::Client {
+    http_client(api.timeout)
+}
+
+/// HTTP client for every request to a provider, the read timeout defaults to two minutes
+pub fn http_client(timeout_secs: Option<u64>) -> reqwest::Client {
+    let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS));
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT.min(timeout))
+        .read_timeout(timeout)
+        .build()
+        .unwrap_or_else(|e| {
+            error!("Cannot build the HTTP client: {}", e.to_string());
+            reqwest::Client::new()
+        })
+}
+
+pub fn from_api(api: &AiApi) -> Box<dyn ChatBackend> {
+    match api.kind {
+        BackendKind::OpenAi => Box::new(openai::OpenAiBackend::new(api.clone())),
+        BackendKind::Ollama => Box::new(ollama::OllamaBackend::new(api.clone())),
+        BackendKind::Anthropic => Box::new(anthropic::AnthropicBackend::new(api.clone())),
+        BackendKind::Mock => Box::new(mock::MockBackend::new(api.clone())),
+    }
+}
+
+/// Whether the model is among the listed ones, Ollama names without a tag stand for the latest one
+pub fn offers(models: &[String], model: &str) -> bool {
+    let tagged = |m: &str| if m.contains(':') { m.to_string() } else { format!("{}:latest", m) };
+    let model = tagged(model.trim());
+    models.iter().any(|m| tagged(m) == model)
+}
+
+/// Running completion. The request is aborted when cancelled or dropped.
+#[derive(Debug)]
+pub struct ChatStream {
+    rx: Receiver<Delta>,
+    task: JoinHandle<()>,
+}
+
+impl ChatStream {
+    /// Runs the producer in its own task, its error ends the stream with a `Delta::Error`
+    pub fn spawn<F, Fut>(f: F) -> Self
+    where
+        F: FnOnce(Sender<Delta>) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        let fut = f(tx.clone());
+        let task = tokio::spawn(async move {
+            if let Err(e) = fut.await {
+                if tx.is_closed() {
+                    debug!("Stream cancelled");
+                } else {
+                    error!("Stream error: {}", e.to_string());
+                    let _ = tx.send(Delta::Error(ChatError::classify(&e))).await;
+                }
+            }
+        });
+        Self { rx, task }
+    }
+
+    pub async fn next(&mut self) -> Option<Delta> {
+        self.rx.recv().await
+    }
+
+    pub fn cancel(&mut self) {
+        self.task.abort();
+        self.rx.close();
+    }
+}
+
+impl Drop for ChatStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Splits a streamed response body into lines
+pub struct Lines<S> {
+    stream: S,
+    buf: Vec<u8>,
+}
+
+impl<S, B> Lines<S>
+where
+    S: Stream<Item = reqwest::Result<B>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    pub fn new(stream: S) -> Self {
+        Self { stream, buf: vec![] }
+    }
+
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        loop {
+            if let Some(i) = self.buf.iter().position(|b| *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=i).collect();
+                return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()));
+            }
+            match self.stream.next().await {
+                Some(bytes) => self.buf.extend_from_slice(bytes?.as_ref()),
+                None if self.buf.is_empty() => return Ok(None),
+                None => {
+                    let line = std::mem::take(&mut self.buf);
+                    return Ok(Some(String::from_utf8_lossy(&line).trim_end().to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Seconds to wait given by a rate limited response
+pub fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Turns an unsuccessful response into a `ChatError` with the body the server sent
+pub async fn check_status(res: reqwest::Response) -> Result<reqwest::Response> {
+    if res.status().is_success() {
+        return Ok(res);
+    }
+    let status = res.status();
+    let retry = retry_after(&res);
+    let body = res.text().await.unwrap_or_default();
+    Err(ChatError::from_status(status, retry, body).into())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use iced::futures::stream;
+    use std::future::Future;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Response of a local server sending the body in the given pieces, one chunk each
+    pub async fn respond(pieces: &[&str]) -> reqwest::Response {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let pieces: Vec<String> = pieces.iter().map(|p| p.to_string()).collect();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0u8; 4096];
+            let _ = socket.read(&mut request).await.unwrap();
+            socket.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n").await.unwrap();
+            for p in pieces {
+                socket.write_all(format!("{:x}\r\n{}\r\n", p.len(), p).as_bytes()).await.unwrap();
+                socket.flush().await.unwrap();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            socket.write_all(b"0\r\n\r\n").await.unwrap();
+        });
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        client.get(url).send().await.unwrap()
+    }
+
+    /// Deltas a backend sends for the response and the error it stops with
+    pub async fn forwarded<F>(res: reqwest::Response, forward: impl FnOnce(reqwest::Response, Sender<Delta>) -> F)
+        -> (Vec<Delta>, Option<ChatError>)
+    where
+        F: Future<Output = Result<()>>,
+    {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        let r = forward(res, tx).await;
+        let mut deltas = vec![];
+        while let Some(d) = rx.recv().await {
+            deltas.push(d);
+        }
+        (deltas, r.err().map(|e| ChatError::classify(&e)))
+    }
+
+    pub async fn lines(pieces: &[&[u8]]) -> Vec<String> {
+        let mut lines = Lines::new(stream::iter(pieces.iter().map(|p| Ok::<_, reqwest::Error>(*p))));
+        let mut res = vec![];
+        while let Some(line) = lines.next().await.unwrap() {
+            res.push(line);
+        }
+        res
+    }
+
+    #[tokio::test]
+    async fn sse_lines_split_between_chunks() {
+        let got = lines(&[b"data: {\"a\"", b":1}\r\n\r\nda", b"ta: [DONE]\n\n"]).await;
+        assert_eq!(got, vec!["data: {\"a\":1}", "", "data: [DONE]", ""]);
+    }
+
+    #[tokio::test]
+    async fn ndjson_lines_split_between_chunks() {
+        let got = lines(&[b"{\"done\":fal", b"se}\n{\"do", b"ne\":true}"]).await;
+        assert_eq!(got, vec!["{\"done\":false}", "{\"done\":true}"]);
+    }
+
+    #[tokio::test]
+    async fn character_split_between_chunks() {
+        let got = lines(&[b"data: caf\xc3", b"\xa9\n"]).await;
+        assert_eq!(got, vec!["data: café"]);
+    }
+}
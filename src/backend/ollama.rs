@@ -0,0 +1,307 @@
+use iced::futures::future::BoxFuture;
+use iced::futures::StreamExt;
+use iced::task::{Sipper, sipper};
+use openai::chat::ChatCompletionMessageRole;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::Sender;
+use anyhow::Result;
+use crate::config::AiApi;
+use crate::usage::Usage;
+use crate::tools::{ToolCall, ToolSpec};
+use crate::chat::ChatMessage;
+use super::{ChatBackend, ChatStream, Delta, Lines, check_status};
+
+/// Ollama's own API, gives access to the installed models and their options
+pub struct OllamaBackend {
+    api: AiApi,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage<'a>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<Value>,
+    options: RequestOptions,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMessage<'a> {
+    role: ChatCompletionMessageRole,
+    content: &'a str,
+    /// Base64 encoded, without the data url prefix
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_ctx: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatChunk {
+    message: Option<ChunkMessage>,
+    #[serde(default)]
+    done: bool,
+    error: Option<String>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkMessage {
+    #[serde(default)]
+    content: String,
+    thinking: Option<String>,
+    tool_calls: Option<Vec<ChunkToolCall>>,
+}
+
+/// Ollama sends each tool call whole, with the arguments as an object and without an id
+#[derive(Debug, Deserialize)]
+struct ChunkToolCall {
+    function: ChunkFunction,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkFunction {
+    name: String,
+    #[serde(default)]
+    arguments: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct TagList {
+    models: Vec<Tag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Tag {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PullStatus {
+    #[serde(default)]
+    status: String,
+    completed: Option<u64>,
+    total: Option<u64>,
+    error: Option<String>,
+}
+
+/// Progress of a model download
+#[derive(Debug, Clone)]
+pub struct PullProgress {
+    pub status: String,
+    pub completed: Option<u64>,
+    pub total: Option<u64>,
+}
+
+impl std::fmt::Display for PullProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.completed, self.total) {
+            (Some(c), Some(t)) if t > 0 => write!(f, "{} {}%", self.status, c * 100 / t),
+            _ => write!(f, "{}", self.status),
+        }
+    }
+}
+
+impl OllamaBackend {
+    pub fn new(api: AiApi) -> Self {
+        Self { client: super::client(&api), api }
+    }
+
+    /// The native API lives next to the OpenAI shim, so an url ending with /v1 works too
+    fn url(&self, path: &str) -> String {
+        let base = self.api.url.trim_end_matches('/');
+        let base = base.strip_suffix("/v1").unwrap_or(base);
+        format!("{}/{}", base, path)
+    }
+
+    /// Downloads a model, reporting the progress on the way
+    pub fn pull(&self, model: String) -> impl Sipper<Result<()>, PullProgress> + use<> {
+        let req = self.client
+            .post(self.url("api/pull"))
+            .json(&ModelRequest { model: model.as_str(), stream: Some(true) });
+        sipper(async move |mut progress| {
+            let res = check_status(req.send().await?).await?;
+            let mut lines = Lines::new(res.bytes_stream().boxed());
+            while let Some(line) = lines.next().await? {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let s: PullStatus = serde_json::from_str(line.as_str())?;
+                if let Some(e) = s.error {
+                    anyhow::bail!(e);
+                }
+                progress.send(PullProgress { status: s.status, completed: s.completed, total: s.total }).await;
+            }
+            Ok::<(), anyhow::Error>(())
+        })
+    }
+
+    pub async fn delete(&self, model: &str) -> Result<()> {
+        let req = self.client
+            .delete(self.url("api/delete"))
+            .json(&ModelRequest { model, stream: None });
+        check_status(req.send().await?).await?;
+        Ok(())
+    }
+}
+
+fn request_message(m: &ChatMessage) -> RequestMessage<'_> {
+    let tool_calls = m.tool_calls.iter()
+        .flatten()
+        .map(|c| {
+            let arguments: Value = serde_json::from_str(c.function.arguments.as_str()).unwrap_or(json!({}));
+            json!({ "function": { "name": c.function.name, "arguments": arguments } })
+        })
+        .collect();
+    RequestMessage {
+        role: m.role,
+        content: m.content.as_deref().unwrap_or_default(),
+        images: m.images.iter().map(|i| i.data.as_str()).collect(),
+        tool_calls,
+    }
+}
+
+async fn forward_stream(res: reqwest::Response, tx: Sender<Delta>) -> Result<()> {
+    let mut lines = Lines::new(res.bytes_stream().boxed());
+    while let Some(line) = lines.next().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chunk: ChatChunk = serde_json::from_str(line.as_str())?;
+        if let Some(e) = chunk.error {
+            anyhow::bail!(e);
+        }
+        if let Some(m) = chunk.message {
+            if let Some(t) = m.thinking.filter(|t| !t.is_empty()) {
+                tx.send(Delta::Reasoning(t)).await?;
+            }
+            if !m.content.is_empty() {
+                tx.send(Delta::Content(m.content)).await?;
+            }
+            for (i, c) in m.tool_calls.unwrap_or_default().into_iter().enumerate() {
+                let id = format!("call_{}_{}", chrono::Utc::now().timestamp_millis(), i);
+                tx.send(Delta::ToolCall(ToolCall { id, name: c.function.name, arguments: c.function.arguments.to_string() })).await?;
+            }
+        }
+        if chunk.done {
+            if let (Some(p), Some(c)) = (chunk.prompt_eval_count, chunk.eval_count) {
+                tx.send(Delta::Usage(Usage { prompt_tokens: p, completion_tokens: c })).await?;
+            }
+            break;
+        }
+    }
+    Ok(())
+}
+
+impl ChatBackend for OllamaBackend {
+    fn stream(&self, messages: &[ChatMessage], tools: &[ToolSpec]) -> BoxFuture<'_, Result<ChatStream>> {
+        let ollama = self.api.ollama.clone().unwrap_or_default();
+        let params = self.api.params.clone().unwrap_or_default();
+        let body = ChatRequest {
+            model: self.api.model.as_str(),
+            messages: messages.iter().map(request_message).collect(),
+            stream: true,
+            keep_alive: ollama.keep_alive.as_deref(),
+            tools: tools.iter().map(|t| t.to_function()).collect(),
+            options: RequestOptions {
+                num_ctx: ollama.num_ctx,
+                temperature: params.temperature,
+                top_p: params.top_p,
+                num_predict: params.max_tokens,
+                presence_penalty: params.presence_penalty,
+                frequency_penalty: params.frequency_penalty,
+                seed: params.seed,
+                stop: params.stop,
+            },
+        };
+        let req = self.client.post(self.url("api/chat")).json(&body);
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            Ok(ChatStream::spawn(|tx| forward_stream(res, tx)))
+        })
+    }
+
+    fn models(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        let req = self.client.get(self.url("api/tags"));
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            let list: TagList = res.json().await?;
+            let mut models: Vec<String> = list.models.into_iter().map(|m| m.name).collect();
+            models.sort();
+            Ok(models)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests::{forwarded, respond};
+    use crate::chat::ChatError;
+
+    #[tokio::test]
+    async fn chunks_split_between_reads() {
+        let res = respond(&[
+            r#"{"message":{"content":"","thinking":"Hm"},"done":false}"#,
+            "\n",
+            r#"{"message":{"content":"Hel"#,
+            r#"lo"},"done":false}"#,
+            "\n",
+            r#"{"message":{"content":"","tool_calls":[{"function":{"name":"clock","arguments":{"tz":"UTC"}}}]},"done":false}"#,
+            "\n",
+            r#"{"message":{"content":""},"done":true,"prompt_eval_count":5,"eval_count":3}"#,
+        ]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert_eq!(e, None);
+        assert_eq!(deltas.len(), 4);
+        assert_eq!(deltas[0], Delta::Reasoning("Hm".to_string()));
+        assert_eq!(deltas[1], Delta::Content("Hello".to_string()));
+        // The id of the call is made up
+        let Delta::ToolCall(call) = &deltas[2] else {
+            panic!("Expected a tool call, got {:?}", deltas[2]);
+        };
+        assert_eq!((call.name.as_str(), call.arguments.as_str()), ("clock", r#"{"tz":"UTC"}"#));
+        assert_eq!(deltas[3], Delta::Usage(Usage { prompt_tokens: 5, completion_tokens: 3 }));
+    }
+
+    #[tokio::test]
+    async fn error_line_ends_the_stream() {
+        let res = respond(&[r#"{"error":"model \"llama9\" not found, try pulling it first"}"#, "\n"]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert!(deltas.is_empty());
+        assert!(matches!(e, Some(ChatError::ModelNotFound(_))));
+    }
+}
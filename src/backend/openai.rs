@@ -0,0 +1,296 @@
+use iced::futures::future::BoxFuture;
+use iced::futures::StreamExt;
+use openai::chat::{ChatCompletionMessageRole, ToolCall as ApiToolCall};
+use serde_json::{json, Value};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Sender;
+use anyhow::Result;
+use crate::config::{AiApi, GenerationParams};
+use crate::usage::Usage;
+use crate::tools::{ToolCall, ToolSpec};
+use crate::chat::{ChatError, ChatMessage};
+use super::{ChatBackend, ChatStream, Delta, Lines, check_status};
+
+/// Any API compatible with OpenAI's chat completions
+pub struct OpenAiBackend {
+    api: AiApi,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamRequest<'a> {
+    model: &'a str,
+    messages: Vec<RequestMessage<'a>>,
+    stream: bool,
+    stream_options: StreamOptions,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<serde_json::Value>,
+    #[serde(flatten)]
+    params: GenerationParams,
+}
+
+#[derive(Debug, Serialize)]
+struct RequestMessage<'a> {
+    role: ChatCompletionMessageRole,
+    content: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<&'a Vec<ApiToolCall>>,
+}
+
+#[derive(Debug, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChunk {
+    #[serde(default)]
+    choices: Vec<StreamChoice>,
+    usage: Option<ChunkUsage>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamChoice {
+    #[serde(default)]
+    delta: StreamDelta,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct StreamDelta {
+    content: Option<String>,
+    #[serde(alias = "reasoning")]
+    reasoning_content: Option<String>,
+    tool_calls: Option<Vec<ToolCallChunk>>,
+}
+
+/// Part of a tool call, the arguments arrive in pieces
+#[derive(Debug, Deserialize)]
+struct ToolCallChunk {
+    #[serde(default)]
+    index: usize,
+    id: Option<String>,
+    function: Option<FunctionChunk>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FunctionChunk {
+    name: Option<String>,
+    arguments: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(api: AiApi) -> Self {
+        Self { client: super::client(&api), api }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/{}", self.api.url.trim_end_matches('/'), path)
+    }
+
+    fn auth(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        if self.api.key.is_empty() {
+            req
+        } else {
+            req.bearer_auth(self.api.key.as_str())
+        }
+    }
+}
+
+/// Images make the content a list of parts
+fn request_message(m: &ChatMessage) -> RequestMessage<'_> {
+    let content = if m.images.is_empty() {
+        json!(m.content)
+    } else {
+        let mut parts = vec![];
+        if let Some(t) = m.content.as_ref().filter(|t| !t.is_empty()) {
+            parts.push(json!({ "type": "text", "text": t }));
+        }
+        for image in m.images.iter() {
+            parts.push(json!({ "type": "image_url", "image_url": { "url": image.data_url() } }));
+        }
+        Value::Array(parts)
+    };
+    RequestMessage {
+        role: m.role,
+        content,
+        tool_call_id: m.tool_call_id.as_deref(),
+        tool_calls: m.tool_calls.as_ref(),
+    }
+}
+
+/// Error sent in place of a chunk, sorted like the status of a failed response
+fn chunk_error(e: &Value) -> ChatError {
+    let message = e.get("message")
+        .and_then(|m| m.as_str())
+        .map(|m| m.to_string())
+        .unwrap_or_else(|| e.to_string());
+    let status = e.get("code")
+        .and_then(|c| c.as_u64())
+        .and_then(|c| u16::try_from(c).ok())
+        .and_then(|c| reqwest::StatusCode::from_u16(c).ok());
+    if let Some(status) = status {
+        return ChatError::from_status(status, None, message);
+    }
+    let kind = e.get("type")
+        .or_else(|| e.get("code"))
+        .and_then(|t| t.as_str())
+        .unwrap_or_default();
+    match kind {
+        "server_error" | "overloaded_error" | "service_unavailable" => ChatError::Server(message),
+        "" => ChatError::from_message(message),
+        kind => ChatError::from_message(format!("{}: {}", kind, message)),
+    }
+}
+
+async fn forward_stream(res: reqwest::Response, tx: Sender<Delta>) -> Result<()> {
+    let mut lines = Lines::new(res.bytes_stream().boxed());
+    let mut calls: Vec<ToolCall> = vec![];
+    while let Some(line) = lines.next().await? {
+        let Some(data) = line.trim().strip_prefix("data:") else {
+            continue;
+        };
+        let data = data.trim();
+        if data == "[DONE]" {
+            break;
+        }
+        let value: Value = serde_json::from_str(data)?;
+        // Providers report failures in the middle of the stream as a chunk of its own
+        if let Some(e) = value.get("error") {
+            return Err(chunk_error(e).into());
+        }
+        let chunk: StreamChunk = serde_json::from_value(value)?;
+        // Comes with the last chunk, which has no choices
+        if let Some(u) = chunk.usage {
+            tx.send(Delta::Usage(Usage { prompt_tokens: u.prompt_tokens, completion_tokens: u.completion_tokens })).await?;
+        }
+        let Some(choice) = chunk.choices.into_iter().next() else {
+            continue;
+        };
+        if let Some(r) = choice.delta.reasoning_content.filter(|r| !r.is_empty()) {
+            tx.send(Delta::Reasoning(r)).await?;
+        }
+        if let Some(c) = choice.delta.content.filter(|c| !c.is_empty()) {
+            tx.send(Delta::Content(c)).await?;
+        }
+        for chunk in choice.delta.tool_calls.unwrap_or_default() {
+            if calls.len() <= chunk.index {
+                calls.resize_with(chunk.index + 1, ToolCall::default);
+            }
+            let call = &mut calls[chunk.index];
+            if let Some(id) = chunk.id {
+                call.id = id;
+            }
+            if let Some(f) = chunk.function {
+                call.name.push_str(f.name.unwrap_or_default().as_str());
+                call.arguments.push_str(f.arguments.unwrap_or_default().as_str());
+            }
+        }
+    }
+    for call in calls {
+        tx.send(Delta::ToolCall(call)).await?;
+    }
+    Ok(())
+}
+
+impl ChatBackend for OpenAiBackend {
+    fn stream(&self, messages: &[ChatMessage], tools: &[ToolSpec]) -> BoxFuture<'_, Result<ChatStream>> {
+        let req = self.client
+            .post(self.url("chat/completions"))
+            .json(&StreamRequest {
+                model: self.api.model.as_str(),
+                messages: messages.iter().map(request_message).collect(),
+                stream: true,
+                stream_options: StreamOptions { include_usage: true },
+                tools: tools.iter().map(|t| t.to_function()).collect(),
+                params: self.api.params.clone().unwrap_or_default(),
+            });
+        let req = self.auth(req);
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            Ok(ChatStream::spawn(|tx| forward_stream(res, tx)))
+        })
+    }
+
+    fn models(&self) -> BoxFuture<'_, Result<Vec<String>>> {
+        let req = self.auth(self.client.get(self.url("models")));
+        Box::pin(async move {
+            let res = check_status(req.send().await?).await?;
+            let list: ModelList = res.json().await?;
+            let mut models: Vec<String> = list.data.into_iter().map(|m| m.id).collect();
+            models.sort();
+            Ok(models)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::tests::{forwarded, respond};
+
+    #[tokio::test]
+    async fn chunks_split_between_reads() {
+        let res = respond(&[
+            r#"data: {"choices":[{"delta":{"reasoning_content":"Hm"}}]}"#,
+            "\n\ndata: ",
+            r#"{"choices":[{"delta":{"content":"Hel"#,
+            r#"lo"}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"id":"c1","function":{"name":"clock","arguments":"{\"tz\""}}]}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":":\"UTC\"}"}}]}}]}"#,
+            "\n\n",
+            r#"data: {"choices":[],"usage":{"prompt_tokens":5,"completion_tokens":3}}"#,
+            "\n\ndata: [DONE]\n\n",
+        ]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert_eq!(e, None);
+        assert_eq!(deltas, vec![
+            Delta::Reasoning("Hm".to_string()),
+            Delta::Content("Hello".to_string()),
+            Delta::Usage(Usage { prompt_tokens: 5, completion_tokens: 3 }),
+            Delta::ToolCall(ToolCall { id: "c1".to_string(), name: "clock".to_string(), arguments: r#"{"tz":"UTC"}"#.to_string() }),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn error_chunk_ends_the_stream() {
+        let res = respond(&[
+            r#"data: {"choices":[{"delta":{"content":"Hi"}}]}"#,
+            "\n\n",
+            r#"data: {"error":{"message":"Slow down","type":"rate_limit_exceeded"}}"#,
+            "\n\n",
+        ]).await;
+        let (deltas, e) = forwarded(res, forward_stream).await;
+        assert_eq!(deltas, vec![Delta::Content("Hi".to_string())]);
+        assert!(matches!(e, Some(ChatError::RateLimit { .. })));
+    }
+
+    #[test]
+    fn error_chunks_are_classified() {
+        let e = chunk_error(&json!({ "message": "Busy", "code": 503 }));
+        assert_eq!(e, ChatError::Server("Busy".to_string()));
+        let e = chunk_error(&json!({ "message": "Down", "type": "server_error" }));
+        assert!(e.retry_delay(0).is_some());
+        let e = chunk_error(&json!({ "message": "Bad key", "code": 401 }));
+        assert_eq!(e.retry_delay(0), None);
+    }
+}
@@ -1,122 +1,1098 @@
 use iced::futures::channel::mpsc;
-use openai::{
-    chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole}, Credentials
-};
+use openai::chat::ChatCompletionMessageRole;
+use serde::{Deserialize, Serialize};
 use iced::task::{Never, Sipper, sipper};
-use crate::config::AiApi;
+use crate::config::{AiApi, ContextPolicy};
+use crate::context;
+use crate::backend::{self, ChatBackend, ChatStream, Delta};
+use crate::usage::{self, ExchangeStats, Usage};
+use crate::utils::estimate_tokens;
+use crate::tools::{ToolCall, ToolRegistry, ToolSpec};
+use crate::attachments::{self, FileAttachment, Image};
+use crate::rag::{Retriever, Source};
+use crate::transcribe::Transcription;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
 use tracing::{debug, error, info};
-use tokio::sync::mpsc::error::TryRecvError;
+use iced::futures::StreamExt;
+
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+/// Answers in a row that may call tools, stops a model from calling them forever
+const MAX_TOOL_ROUNDS: usize = 8;
+const MAX_RETRIES: u32 = 3;
+const BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const SUMMARY_PROMPT: &str = "Summarise the conversation you are given in at most 200 words. \
+Keep the facts, names, numbers, decisions and open questions. Answer with the summary only.";
 
 #[derive(Debug, Clone)]
 pub enum ChatCommand {
     SetChat(AiApi),
     SetContext(String),
-    Prompt(String),
+    Prompt { text: String, images: Vec<Image>, files: Vec<FileAttachment>, transcription: Option<Transcription> },
+    NewConversation,
+    LoadHistory(Vec<ChatMessage>),
+    /// Asks for another answer to the last message of the history
+    Regenerate,
+    /// Documents searched for each prompt, None stops using them
+    SetRetriever(Option<Retriever>),
     Stop,
+    /// Answer to a `ToolRequest`
+    ToolDecision { id: String, allow: bool },
 }
 
 #[derive(Debug, Clone)]
 pub enum ChatEvent {
     ChatReady(mpsc::Sender<ChatCommand>),
     ChatMessage(String),
-    ChatError(String),
+    /// Part of the reasoning of a thinking model, not part of the answer
+    Reasoning(String),
+    ChatError(ChatError),
+    /// Tokens of the request about to be sent, the oldest messages may have been left out to fit
+    ContextUsage { used: u64, limit: Option<u64>, left_out: usize },
+    /// A transient error, the request is sent again after the delay
+    Retrying { error: ChatError, attempt: u32, delay: Duration },
+    /// Passages of the documents given to the model with the prompt, the answer cites them by number
+    Sources(Vec<Source>),
+    /// Tokens, timing and cost of the answer, sent before it is added to the history
+    Stats(ExchangeStats),
+    HistoryUpdated(Vec<ChatMessage>),
+    /// The model wants to call a tool, waits for a `ToolDecision`
+    ToolRequest(ToolCall),
+    /// The tool call was answered, the result goes back to the model
+    ToolDone { call: ToolCall, allowed: bool },
+    /// The stream was stopped before the answer was complete
+    Truncated,
     StreamEnded,
 }
 
+/// Failure of a chat request, sorted by what the user can do about it
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatError {
+    Auth(String),
+    RateLimit { retry_after: Option<Duration>, message: String },
+    Timeout,
+    ConnectionRefused(String),
+    ModelNotFound(String),
+    ContextLength(String),
+    /// The provider failed or is overloaded
+    Server(String),
+    Other(String),
+}
+
+impl fmt::Display for ChatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChatError::Auth(m) => write!(f, "Authentication failed: {}", m),
+            ChatError::RateLimit { message, .. } => write!(f, "Rate limited: {}", message),
+            ChatError::Timeout => write!(f, "The request timed out"),
+            ChatError::ConnectionRefused(m) => write!(f, "Cannot connect: {}", m),
+            ChatError::ModelNotFound(m) => write!(f, "Model not found: {}", m),
+            ChatError::ContextLength(m) => write!(f, "Context length exceeded: {}", m),
+            ChatError::Server(m) => write!(f, "Provider error: {}", m),
+            ChatError::Other(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+impl std::error::Error for ChatError {}
+
+impl ChatError {
+    /// Sorts an unsuccessful response by its status, the body tells apart the different bad requests
+    pub fn from_status(status: reqwest::StatusCode, retry_after: Option<Duration>, message: String) -> Self {
+        match status.as_u16() {
+            401 | 403 => ChatError::Auth(message),
+            429 => ChatError::RateLimit { retry_after, message },
+            404 => ChatError::ModelNotFound(message),
+            408 | 504 => ChatError::Timeout,
+            500.. => ChatError::Server(message),
+            _ => match Self::from_message(message) {
+                ChatError::Other(m) => ChatError::Other(format!("{}: {}", status, m)),
+                e => e,
+            },
+        }
+    }
+
+    /// Errors reported in the middle of a stream only have a message
+    pub fn from_message(message: String) -> Self {
+        let m = message.to_lowercase();
+        if m.contains("context length") || m.contains("context_length") || m.contains("maximum context")
+            || m.contains("prompt is too long") || m.contains("too many tokens") {
+            ChatError::ContextLength(message)
+        } else if m.contains("rate limit") || m.contains("rate_limit") {
+            ChatError::RateLimit { retry_after: None, message }
+        } else if m.contains("overloaded") {
+            ChatError::Server(message)
+        } else if m.contains("model") && m.contains("not found") {
+            ChatError::ModelNotFound(message)
+        } else {
+            ChatError::Other(message)
+        }
+    }
+
+    pub fn classify(e: &anyhow::Error) -> Self {
+        if let Some(c) = e.downcast_ref::<ChatError>() {
+            return c.clone();
+        }
+        if let Some(r) = e.downcast_ref::<reqwest::Error>() {
+            if r.is_timeout() {
+                return ChatError::Timeout;
+            }
+            if r.is_connect() {
+                return ChatError::ConnectionRefused(r.to_string());
+            }
+        }
+        Self::from_message(e.to_string())
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ChatError::RateLimit { .. } | ChatError::Timeout | ChatError::Server(_))
+    }
+
+    /// Delay before the given retry, None when the request should not be repeated
+    pub fn retry_delay(&self, attempt: u32) -> Option<Duration> {
+        if !self.is_transient() || attempt >= MAX_RETRIES {
+            return None;
+        }
+        let delay = match self {
+            ChatError::RateLimit { retry_after: Some(d), .. } => *d,
+            _ => Duration::from_millis(BACKOFF_MS << attempt),
+        };
+        Some(delay.min(MAX_BACKOFF))
+    }
+
+    /// What the user can do about the error
+    pub fn hint(&self) -> &str {
+        match self {
+            ChatError::Auth(_) => "Check the API key of this chat in Settings.",
+            ChatError::RateLimit { .. } => "The provider limits how often you can ask. Wait a moment before asking again or check the limits of your plan.",
+            ChatError::Timeout => "The provider did not answer in time. Ask again or raise the request timeout of this chat in Settings.",
+            ChatError::ConnectionRefused(_) => "Check the Api Url in Settings and that the server, e.g. Ollama, is running.",
+            ChatError::ModelNotFound(_) => "Check the model in Settings, Fetch models lists the available ones. Ollama models have to be pulled first.",
+            ChatError::ContextLength(_) => "The conversation is too long for this model. Start a new conversation or pick a model with a bigger context.",
+            ChatError::Server(_) => "The provider has problems right now. Try again later.",
+            ChatError::Other(_) => "",
+        }
+    }
+}
+
+/// Message of the conversation, stored in the form of OpenAI's messages with the images added
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: ChatCompletionMessageRole,
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<Image>,
+    /// Put into the content only when sent, so the text of the message stays as typed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileAttachment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<openai::chat::ToolCall>>,
+    /// Recording the prompt was dictated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcription: Option<Transcription>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<chrono::DateTime<chrono::Utc>>,
+    /// The answer was stopped before it ended
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+impl ChatMessage {
+    /// The message as sent to the provider, with the attached files in the content
+    pub fn expanded(&self) -> ChatMessage {
+        if self.files.is_empty() {
+            return self.clone();
+        }
+        let content = attachments::inject(self.content.as_deref().unwrap_or_default(), &self.files);
+        ChatMessage { content: Some(content), files: vec![], ..self.clone() }
+    }
+}
+
+pub fn message(role: ChatCompletionMessageRole, content: impl Into<String>) -> ChatMessage {
+    ChatMessage {
+        role,
+        content: Some(content.into()),
+        images: vec![],
+        files: vec![],
+        tool_call_id: None,
+        tool_calls: None,
+        transcription: None,
+        time: Some(chrono::Utc::now()),
+        truncated: false,
+    }
+}
+
+/// Moves the streamed answer, even a partial one, into the conversation
+fn push_answer(history: &mut Vec<ChatMessage>, answer: &mut String) -> bool {
+    if answer.is_empty() {
+        return false;
+    }
+    history.push(message(ChatCompletionMessageRole::Assistant, std::mem::take(answer)));
+    true
+}
+
+/// Keeps what was streamed of an answer cut off by the user, marked as truncated.
+/// A prompt with nothing answered yet is taken back, so that no two prompts follow each other.
+fn cut_answer(history: &mut Vec<ChatMessage>, answer: &mut String) -> bool {
+    if push_answer(history, answer) {
+        if let Some(m) = history.last_mut() {
+            m.truncated = true;
+        }
+        return true;
+    }
+    if history.last().is_some_and(|m| m.role == ChatCompletionMessageRole::User) {
+        history.pop();
+        return true;
+    }
+    false
+}
+
+/// Answers the tool calls still waiting for a decision, the conversation is invalid without their results
+fn cancel_tools(history: &mut Vec<ChatMessage>, pending: &mut VecDeque<ToolCall>) -> bool {
+    if pending.is_empty() {
+        return false;
+    }
+    for call in pending.drain(..) {
+        history.push(tool_result(call.id, "Cancelled by the user"));
+    }
+    true
+}
+
+fn tool_result(id: String, result: impl Into<String>) -> ChatMessage {
+    let mut m = message(ChatCompletionMessageRole::Tool, result);
+    m.tool_call_id = Some(id);
+    m
+}
+
+pub async fn request(ch: &dyn ChatBackend, messages: &[ChatMessage], tools: &[ToolSpec])
+    -> Result<(ChatStream, Exchange), ChatError>
+{
+    let ex = Exchange::new(messages);
+    let s = ch.stream(messages, tools).await
+        .map_err(|e| ChatError::classify(&e))?;
+    Ok((s, ex))
+}
+
+/// Streams the answer to a request made apart from the conversation, without tools since their calls cannot be confirmed
+pub fn answer(api: AiApi, messages: Vec<ChatMessage>) -> impl Sipper<Result<ExchangeStats, ChatError>, String> {
+    sipper(async move |mut output| {
+        let ch = backend::from_api(&api);
+        let (mut s, mut ex) = request(&*ch, &messages, &[]).await?;
+        let mut think = ThinkSplitter::default();
+        loop {
+            let d = s.next().await;
+            let ended = d.is_none();
+            let parts = match d {
+                Some(Delta::Content(c)) => think.feed(c.as_str()),
+                Some(d) => vec![d],
+                None => think.flush(),
+            };
+            for part in parts {
+                match part {
+                    Delta::Content(c) => {
+                        ex.token(c.as_str());
+                        output.send(c).await;
+                    }
+                    Delta::Reasoning(r) => ex.token(r.as_str()),
+                    Delta::Usage(u) => ex.usage = Some(u),
+                    Delta::ToolCall(_) => {}
+                    Delta::Error(e) => return Err(e),
+                }
+            }
+            if ended {
+                break;
+            }
+        }
+        Ok(ex.stats(&api))
+    })
+}
+
+/// Whole answer to a request made apart from the conversation, without tools and reasoning
+pub async fn complete(api: &AiApi, messages: &[ChatMessage]) -> Result<(String, ExchangeStats), ChatError> {
+    let mut s = answer(api.clone(), messages.to_vec()).pin();
+    let mut text = String::new();
+    while let Some(c) = s.sip().await {
+        text.push_str(c.as_str());
+    }
+    let stats = s.await?;
+    Ok((text, stats))
+}
+
+/// Summary of the first messages of the history
+struct Summary {
+    upto: usize,
+    text: String,
+}
+
+/// Summarises the messages left out of the context, extending the previous summary when there is one
+async fn summarise(ch: &dyn ChatBackend, cache: &mut Option<Summary>, old: &[ChatMessage]) -> Result<String, ChatError> {
+    let (previous, rest) = match cache.as_ref() {
+        Some(s) if s.upto == old.len() => return Ok(s.text.clone()),
+        Some(s) if s.upto < old.len() => (Some(s.text.clone()), &old[s.upto..]),
+        _ => (None, old),
+    };
+    let mut text = String::new();
+    if let Some(p) = previous {
+        text.push_str(format!("Summary of what came before: {}\n\n", p).as_str());
+    }
+    text.push_str(context::transcript(rest).as_str());
+    let messages = [
+        message(ChatCompletionMessageRole::System, SUMMARY_PROMPT),
+        message(ChatCompletionMessageRole::User, text),
+    ];
+    let mut s = ch.stream(&messages, &[]).await
+        .map_err(|e| ChatError::classify(&e))?;
+    let mut think = ThinkSplitter::default();
+    let mut parts = vec![];
+    while let Some(d) = s.next().await {
+        match d {
+            Delta::Content(c) => parts.extend(think.feed(c.as_str())),
+            Delta::Error(e) => return Err(e),
+            _ => {}
+        }
+    }
+    parts.extend(think.flush());
+    let mut res = String::new();
+    for p in parts {
+        if let Delta::Content(c) = p {
+            res.push_str(c.as_str());
+        }
+    }
+    let res = res.trim().to_string();
+    *cache = Some(Summary { upto: old.len(), text: res.clone() });
+    Ok(res)
+}
+
+/// Returns the command put aside while waiting to retry before the new ones
+async fn next_command(deferred: &mut Option<ChatCommand>, receiver: &mut mpsc::Receiver<ChatCommand>) -> Option<ChatCommand> {
+    match deferred.take() {
+        Some(c) => Some(c),
+        None => receiver.next().await,
+    }
+}
+
+/// Waits for the future unless a command comes first, which is put aside for the worker
+async fn unless_command<T>(
+    fut: impl std::future::Future<Output = T>,
+    deferred: &mut Option<ChatCommand>,
+    receiver: &mut mpsc::Receiver<ChatCommand>,
+) -> Option<T> {
+    tokio::select! {
+        r = fut => Some(r),
+        m = receiver.next() => {
+            *deferred = m;
+            None
+        }
+    }
+}
+
+/// Takes back the new prompt ending the history, true when it was there
+fn take_back(prompted: &mut bool, history: &mut Vec<ChatMessage>) -> bool {
+    if std::mem::take(prompted) && history.last().is_some_and(|m| m.role == ChatCompletionMessageRole::User) {
+        history.pop();
+        return true;
+    }
+    false
+}
+
+/// Separates `<think>...</think>` sections from the content, tags may be split between chunks
+#[derive(Debug, Default)]
+pub struct ThinkSplitter {
+    buf: String,
+    in_think: bool,
+}
+
+impl ThinkSplitter {
+    fn emit(&self, s: &str, out: &mut Vec<Delta>) {
+        if s.is_empty() {
+            return;
+        }
+        if self.in_think {
+            out.push(Delta::Reasoning(s.to_string()));
+        } else {
+            out.push(Delta::Content(s.to_string()));
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &str) -> Vec<Delta> {
+        self.buf.push_str(chunk);
+        let mut out = vec![];
+        loop {
+            let tag = if self.in_think { THINK_END } else { THINK_START };
+            if let Some(i) = self.buf.find(tag) {
+                let before = self.buf[..i].to_string();
+                self.emit(before.as_str(), &mut out);
+                self.buf.drain(..i + tag.len());
+                self.in_think = !self.in_think;
+            } else {
+                // Keep what could be the beginning of a tag for the next chunk
+                let keep = (1..tag.len()).rev()
+                    .find(|k| self.buf.ends_with(&tag[..*k]))
+                    .unwrap_or(0);
+                let done = self.buf.len() - keep;
+                let part = self.buf[..done].to_string();
+                self.emit(part.as_str(), &mut out);
+                self.buf.drain(..done);
+                break;
+            }
+        }
+        out
+    }
+
+    pub fn flush(&mut self) -> Vec<Delta> {
+        let mut out = vec![];
+        let rest = std::mem::take(&mut self.buf);
+        self.emit(rest.as_str(), &mut out);
+        self.in_think = false;
+        out
+    }
+}
+
+/// Timing and token counts of the answer being streamed
+pub struct Exchange {
+    started: Instant,
+    first_token: Option<Instant>,
+    pub usage: Option<Usage>,
+    prompt_tokens: u64,
+    generated: String,
+}
+
+impl Exchange {
+    pub fn new(messages: &[ChatMessage]) -> Self {
+        let prompt_tokens = context::count(messages);
+        Self {
+            started: Instant::now(),
+            first_token: None,
+            usage: None,
+            prompt_tokens,
+            generated: String::new(),
+        }
+    }
+
+    pub fn token(&mut self, s: &str) {
+        if self.first_token.is_none() {
+            self.first_token = Some(Instant::now());
+        }
+        self.generated.push_str(s);
+    }
+
+    pub fn stats(&self, api: &AiApi) -> ExchangeStats {
+        let now = Instant::now();
+        let (usage, estimated) = match self.usage {
+            Some(u) => (u, false),
+            None => (Usage { prompt_tokens: self.prompt_tokens, completion_tokens: estimate_tokens(self.generated.as_str()) }, true),
+        };
+        let tokens_per_sec = self.first_token.and_then(|t| {
+            let secs = (now - t).as_secs_f32();
+            (secs > 0.0 && usage.completion_tokens > 0).then(|| usage.completion_tokens as f32 / secs)
+        });
+        ExchangeStats {
+            provider: api.name.clone(),
+            model: api.model.clone(),
+            usage,
+            estimated,
+            ttft_ms: self.first_token.map(|t| (t - self.started).as_millis() as u64),
+            duration_ms: (now - self.started).as_millis() as u64,
+            tokens_per_sec,
+            cost: usage::cost(api.pricing.as_ref(), &usage),
+        }
+    }
+}
+
+/// Waits for the next chunk of the current answer, forever if nothing is streaming
+async fn next_delta(cc: &mut Option<ChatStream>) -> Option<Delta> {
+    match cc.as_mut() {
+        Some(r) => r.next().await,
+        None => std::future::pending().await,
+    }
+}
+
 pub fn connect() -> impl Sipper<Never, ChatEvent> {
     sipper(async |mut output| {
         let (sender, mut receiver) = mpsc::channel::<ChatCommand>(100);
         output.send(ChatEvent::ChatReady(sender)).await;
         let mut context: Option<String> = None;
-        let mut ch: Option<AiApi> = None;
-        let mut cc = None;
+        let mut api: Option<AiApi> = None;
+        let mut ch: Option<Box<dyn ChatBackend>> = None;
+        let mut cc: Option<ChatStream> = None;
+        let mut exchange: Option<Exchange> = None;
+        let mut think = ThinkSplitter::default();
+        // Running conversation, without the system context which is prepended on each request
+        let mut history: Vec<ChatMessage> = vec![];
+        let mut answer = String::new();
+        let registry = ToolRegistry::builtin();
+        // Tool calls of the answer being streamed, then the ones waiting for a decision
+        let mut calls: Vec<ToolCall> = vec![];
+        let mut pending: VecDeque<ToolCall> = VecDeque::new();
+        let mut rounds = 0;
+        // The history is ready to be sent, it is done after handling the command
+        let mut send = false;
+        let mut deferred: Option<ChatCommand> = None;
+        let mut summary: Option<Summary> = None;
+        let mut retriever: Option<Retriever> = None;
+        // Passages found for the last prompt, kept for the requests after its tool calls
+        let mut grounding: Option<String> = None;
+        let mut retrieve = false;
+        // The history ends with a new prompt, it is taken back when it gets no answer
+        let mut prompted = false;
+        // Retries of the current request, and the error of a stream that failed before answering anything
+        let mut attempt: u32 = 0;
+        let mut failed: Option<ChatError> = None;
+        let mut retry: Option<ChatError> = None;
 
         loop {
-            let msg = receiver.try_next();
-            match msg {
-                Ok(m) => {
+            tokio::select! {
+                m = next_command(&mut deferred, &mut receiver) => {
                     match m {
-                        Some(ChatCommand::Prompt(pr)) => {
+                        Some(ChatCommand::Prompt { text: pr, images, files, transcription }) => {
                             info!("Received prompt: {}", pr);
-                            let ch = ch.as_ref().unwrap();
-                            let c = Credentials::new(ch.key.as_str(), ch.url.as_str());
-                            let mut messages = vec![];
-                            if let Some(context) = &context {
-                                messages.push(ChatCompletionMessage {
-                                    role: ChatCompletionMessageRole::System,
-                                    content: Some(context.clone()),
-                                    name: None,
-                                    function_call: None,
-                                    tool_call_id: None, 
-                                    tool_calls: None 
-                                });
-                            }
-
-                            messages.push(ChatCompletionMessage { 
-                                role: ChatCompletionMessageRole::User,
-                                content: Some(pr), 
-                                name: None, 
-                                function_call: None, 
-                                tool_call_id: None, 
-                                tool_calls: None 
-                            });
-
-                            cc = Some(ChatCompletion::builder(ch.model.as_str(), messages.clone())
-                                .credentials(c.clone())
-                                .stream(true)
-                                .create_stream()
-                                .await);
+                            failed = None;
+                            if cc.take().is_some() {
+                                // Dropping the stream aborts the request of the previous prompt
+                                info!("Cancelling the previous prompt");
+                                output.send(ChatEvent::Truncated).await;
+                                if let (Some(ex), Some(api)) = (exchange.take(), api.as_ref()) {
+                                    output.send(ChatEvent::Stats(ex.stats(api))).await;
+                                }
+                                if cut_answer(&mut history, &mut answer) {
+                                    output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                                }
+                            }
+                            calls.clear();
+                            if cancel_tools(&mut history, &mut pending) {
+                                output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                            }
+                            if ch.is_none() {
+                                output.send(ChatEvent::ChatError(ChatError::Other("No AI chat selected".to_string()))).await;
+                                output.send(ChatEvent::StreamEnded).await;
+                                continue;
+                            }
+
+                            history.push(ChatMessage { images, files, transcription, ..message(ChatCompletionMessageRole::User, pr) });
+                            answer.clear();
+                            think = ThinkSplitter::default();
+                            rounds = 0;
+                            retrieve = true;
+                            prompted = true;
+                            attempt = 0;
+                            send = true;
+                        }
+                        Some(ChatCommand::Regenerate) => {
+                            if ch.is_none() {
+                                output.send(ChatEvent::ChatError(ChatError::Other("No AI chat selected".to_string()))).await;
+                                output.send(ChatEvent::StreamEnded).await;
+                                continue;
+                            }
+                            if history.last().is_none_or(|m| m.role == ChatCompletionMessageRole::Assistant) {
+                                debug!("Nothing to answer");
+                                output.send(ChatEvent::StreamEnded).await;
+                                continue;
+                            }
+                            answer.clear();
+                            think = ThinkSplitter::default();
+                            rounds = 0;
+                            retrieve = true;
+                            prompted = false;
+                            attempt = 0;
+                            send = true;
+                        }
+                        Some(ChatCommand::SetRetriever(r)) => {
+                            info!("Documents {}", if r.is_some() { "used" } else { "not used" });
+                            retriever = r;
+                        }
+                        Some(ChatCommand::ToolDecision { id, allow }) => {
+                            if pending.front().is_none_or(|c| c.id != id) {
+                                debug!("No pending tool call {}", id);
+                                continue;
+                            }
+                            let Some(call) = pending.pop_front() else {
+                                continue;
+                            };
+                            let result = if allow {
+                                info!("Calling {}({})", call.name, call.arguments);
+                                match registry.call(&call) {
+                                    Ok(r) => r,
+                                    Err(e) => format!("Error: {}", e),
+                                }
+                            } else {
+                                "The user did not allow this call".to_string()
+                            };
+                            history.push(tool_result(call.id.clone(), result));
+                            output.send(ChatEvent::ToolDone { call, allowed: allow }).await;
+                            if let Some(next) = pending.front() {
+                                output.send(ChatEvent::ToolRequest(next.clone())).await;
+                                continue;
+                            }
+                            // Every call has its result, the model can go on with the answer
+                            output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                            rounds += 1;
+                            prompted = false;
+                            attempt = 0;
+                            send = true;
                         }
                         Some(ChatCommand::Stop) => {
                             info!("Told to stop chat");
-                            cc = None;
+                            failed = None;
+                            if let Some(mut s) = cc.take() {
+                                s.cancel();
+                                calls.clear();
+                                output.send(ChatEvent::Truncated).await;
+                                if let (Some(ex), Some(api)) = (exchange.take(), api.as_ref()) {
+                                    output.send(ChatEvent::Stats(ex.stats(api))).await;
+                                }
+                                if cut_answer(&mut history, &mut answer) {
+                                    output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                                }
+                                prompted = false;
+                                output.send(ChatEvent::StreamEnded).await;
+                            } else if cancel_tools(&mut history, &mut pending) {
+                                output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                                output.send(ChatEvent::StreamEnded).await;
+                            }
+                        }
+                        Some(ChatCommand::NewConversation) => {
+                            info!("Starting new conversation");
+                            exchange = None;
+                            failed = None;
+                            summary = None;
+                            grounding = None;
+                            calls.clear();
+                            let busy = !pending.is_empty();
+                            pending.clear();
+                            if cc.take().is_some() || busy {
+                                output.send(ChatEvent::StreamEnded).await;
+                            }
+                            history.clear();
+                            answer.clear();
+                        }
+                        Some(ChatCommand::LoadHistory(h)) => {
+                            info!("Loading history: {} messages", h.len());
+                            exchange = None;
+                            failed = None;
+                            summary = None;
+                            grounding = None;
+                            calls.clear();
+                            let busy = !pending.is_empty();
+                            pending.clear();
+                            if cc.take().is_some() || busy {
+                                output.send(ChatEvent::StreamEnded).await;
+                            }
+                            history = h;
+                            answer.clear();
                         }
                         Some(ChatCommand::SetChat(chat)) => {
-                            ch = Some(chat);
+                            info!("Using {} backend for {}", chat.kind, chat.name);
+                            ch = Some(backend::from_api(&chat));
+                            api = Some(chat);
                         }
                         Some(ChatCommand::SetContext(ctx)) => {
-                            context = Some(ctx);
+                            context = (!ctx.is_empty()).then_some(ctx);
                         }
-                        _ => {
-                            debug!("Should not happen to be None");
+                        None => {
+                            error!("Chat command channel closed");
+                            std::future::pending::<()>().await;
                         }
                     }
                 }
-                Err(_e) => { // We receive messages here: No commands
-                    if let Some(ccs) = cc.as_mut() {
-                        match ccs.as_mut() {
-                            Ok(ccs) => {
-                                let mut d = true;
-                                while d {
-                                    let r = ccs.try_recv();
-                                    match r {
-                                        Ok(r) => {
-                                            let choice = &r.choices[0];
-                                            if let Some(content) = &choice.delta.content {
-                                                debug!("Received content: {}", content);
-                                                output.send(ChatEvent::ChatMessage(content.clone())).await;
-                                            }
-                                        }
-                                        Err(TryRecvError::Empty) => {
-                                            tokio::time::sleep(tokio::time::Duration::from_millis(350)).await;
-                                        }
-                                        Err(TryRecvError::Disconnected) => {
-                                            debug!("** DC **");
-                                            d = false;
-                                        }
-                                    }
+                r = next_delta(&mut cc) => {
+                    let ended = r.is_none();
+                    let parts = match r {
+                        Some(Delta::Content(c)) => think.feed(c.as_str()),
+                        Some(d) => vec![d],
+                        None => think.flush(),
+                    };
+                    for part in parts {
+                        match part {
+                            Delta::Content(content) => {
+                                debug!("Received content: {}", content);
+                                if let Some(ex) = exchange.as_mut() {
+                                    ex.token(content.as_str());
+                                }
+                                answer.push_str(content.as_str());
+                                output.send(ChatEvent::ChatMessage(content)).await;
+                            }
+                            Delta::Reasoning(r) => {
+                                if let Some(ex) = exchange.as_mut() {
+                                    ex.token(r.as_str());
+                                }
+                                output.send(ChatEvent::Reasoning(r)).await;
+                            }
+                            Delta::Usage(u) => {
+                                if let Some(ex) = exchange.as_mut() {
+                                    ex.usage = Some(u);
+                                }
+                            }
+                            Delta::ToolCall(call) => {
+                                debug!("Tool call: {}({})", call.name, call.arguments);
+                                if let Some(ex) = exchange.as_mut() {
+                                    ex.token(call.arguments.as_str());
                                 }
+                                calls.push(call);
                             }
-                            Err(e) => {
-                                error!("Error requesting: {}", e.to_string());
-                                output.send(ChatEvent::ChatError(e.to_string())).await;
+                            Delta::Error(e) => {
+                                // Nothing was answered yet, so the request can be sent again
+                                if answer.is_empty() && calls.is_empty() && e.retry_delay(attempt).is_some() {
+                                    failed = Some(e);
+                                } else {
+                                    output.send(ChatEvent::ChatError(e)).await;
+                                }
                             }
                         }
                     }
-                    tokio::time::sleep(tokio::time::Duration::from_millis(400)).await;
-                    cc = None;
+                    if ended && failed.is_some() {
+                        // Sent again below, after the delay of the error
+                        cc = None;
+                        exchange = None;
+                        retry = failed.take();
+                        send = true;
+                    } else if ended {
+                        debug!("** DC **");
+                        cc = None;
+                        prompted = false;
+                        if let (Some(ex), Some(api)) = (exchange.take(), api.as_ref()) {
+                            output.send(ChatEvent::Stats(ex.stats(api))).await;
+                        }
+                        if !calls.is_empty() && rounds < MAX_TOOL_ROUNDS {
+                            // The calls are part of the answer, each one is confirmed in turn
+                            let mut m = message(ChatCompletionMessageRole::Assistant, std::mem::take(&mut answer));
+                            if m.content.as_ref().is_some_and(|c| c.is_empty()) {
+                                m.content = None;
+                            }
+                            m.tool_calls = Some(calls.iter().map(|c| c.to_api()).collect());
+                            history.push(m);
+                            output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                            pending.extend(calls.drain(..));
+                            if let Some(next) = pending.front() {
+                                output.send(ChatEvent::ToolRequest(next.clone())).await;
+                            }
+                            continue;
+                        }
+                        if !calls.is_empty() {
+                            calls.clear();
+                            let e = format!("The model kept calling tools, stopped after {} rounds", MAX_TOOL_ROUNDS);
+                            output.send(ChatEvent::ChatError(ChatError::Other(e))).await;
+                        }
+                        if push_answer(&mut history, &mut answer) {
+                            output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                        }
+                        output.send(ChatEvent::StreamEnded).await;
+                    }
                 }
             }
+
+            if !std::mem::take(&mut send) {
+                continue;
+            }
+            let Some(ch) = ch.as_ref() else {
+                output.send(ChatEvent::StreamEnded).await;
+                continue;
+            };
+            let tools = if api.as_ref().is_some_and(|a| a.tools) { registry.specs() } else { vec![] };
+
+            if std::mem::take(&mut retrieve) {
+                grounding = None;
+                if let Some(r) = retriever.as_ref() {
+                    let prompt = history.iter()
+                        .rev()
+                        .find(|m| m.role == ChatCompletionMessageRole::User)
+                        .and_then(|m| m.content.clone())
+                        .unwrap_or_default();
+                    match unless_command(r.retrieve(prompt.as_str()), &mut deferred, &mut receiver).await {
+                        // Stop or another prompt came while searching
+                        None => {}
+                        Some(Ok((_, sources))) if sources.is_empty() => debug!("No passages found"),
+                        Some(Ok((text, sources))) => {
+                            info!("Found {} passages", sources.len());
+                            grounding = Some(text);
+                            output.send(ChatEvent::Sources(sources)).await;
+                        }
+                        Some(Err(e)) => {
+                            // The prompt is answered without the documents then
+                            error!("Cannot search the documents: {}", e.to_string());
+                            let e = format!("Cannot search the documents: {}", e);
+                            output.send(ChatEvent::ChatError(ChatError::Other(e))).await;
+                        }
+                    }
+                }
+            }
+
+            let mut messages = vec![];
+            if let Some(context) = &context {
+                messages.push(message(ChatCompletionMessageRole::System, context.clone()));
+            }
+            if let Some(g) = &grounding {
+                messages.push(message(ChatCompletionMessageRole::System, g.clone()));
+            }
+            let mut start = 0;
+            if let Some((api, budget)) = api.as_ref().and_then(|a| context::budget(a).map(|b| (a, b))) {
+                start = context::first_kept(&history, budget.saturating_sub(context::count(&messages)));
+                if start > 0 {
+                    info!("Leaving out {} old messages to fit the context", start);
+                }
+                if start > 0 && api.context_policy == ContextPolicy::Summarise && deferred.is_none() {
+                    match unless_command(summarise(&**ch, &mut summary, &history[..start]), &mut deferred, &mut receiver).await {
+                        None => {}
+                        Some(Ok(text)) => {
+                            let text = format!("Summary of the earlier conversation: {}", text);
+                            messages.push(message(ChatCompletionMessageRole::System, text));
+                        }
+                        // The old messages are dropped then
+                        Some(Err(e)) => error!("Cannot summarise: {}", e.to_string()),
+                    }
+                }
+            }
+            if deferred.is_some() {
+                info!("Interrupted before requesting");
+                if take_back(&mut prompted, &mut history) {
+                    output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                }
+                output.send(ChatEvent::StreamEnded).await;
+                continue;
+            }
+            messages.extend(history[start..].iter().map(|m| m.expanded()));
+            let limit = api.as_ref().and_then(|a| a.context_limit());
+            output.send(ChatEvent::ContextUsage { used: context::count(&messages), limit, left_out: start }).await;
+
+            let failure = loop {
+                if let Some(e) = retry.take() {
+                    let Some(delay) = e.retry_delay(attempt) else {
+                        break Some(e);
+                    };
+                    attempt += 1;
+                    info!("Retrying in {:?}, attempt {}", delay, attempt);
+                    output.send(ChatEvent::Retrying { error: e.clone(), attempt, delay }).await;
+                    // Any command, like Stop or another prompt, gives up on retrying
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        m = receiver.next() => {
+                            deferred = m;
+                            break Some(e);
+                        }
+                    }
+                }
+                match request(&**ch, &messages, &tools).await {
+                    Ok((r, ex)) => {
+                        cc = Some(r);
+                        exchange = Some(ex);
+                        break None;
+                    }
+                    Err(e) => {
+                        error!("Error requesting: {}", e.to_string());
+                        retry = Some(e);
+                    }
+                }
+            };
+            if let Some(e) = failure {
+                // The prompt never got an answer, do not keep it in the conversation.
+                // Regenerated answers and tool results keep what the history already had.
+                if take_back(&mut prompted, &mut history) {
+                    output.send(ChatEvent::HistoryUpdated(history.clone())).await;
+                }
+                if deferred.is_none() {
+                    output.send(ChatEvent::ChatError(e)).await;
+                }
+                output.send(ChatEvent::StreamEnded).await;
+            }
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iced::futures::SinkExt;
+    use crate::backend::mock::FLAKY_MODEL;
+    use crate::config::BackendKind;
+
+    fn mock(model: &str) -> AiApi {
+        AiApi { name: "Mock".to_string(), model: model.to_string(), kind: BackendKind::Mock, ..AiApi::default() }
+    }
+
+    /// The worker talking to the mock backend, with the channel for its commands
+    async fn worker(api: AiApi) -> (impl Sipper<Never, ChatEvent> + Unpin, mpsc::Sender<ChatCommand>) {
+        let mut events = connect().pin();
+        let Some(ChatEvent::ChatReady(mut sender)) = events.sip().await else {
+            panic!("The worker did not start");
+        };
+        sender.send(ChatCommand::SetChat(api)).await.unwrap();
+        (events, sender)
+    }
+
+    fn prompt(text: &str) -> ChatCommand {
+        ChatCommand::Prompt { text: text.to_string(), images: vec![], files: vec![], transcription: None }
+    }
+
+    /// Events until the stream ends, the first answer piece is followed by the command when one is given
+    async fn events_until_ended(
+        events: &mut (impl Sipper<Never, ChatEvent> + Unpin),
+        sender: &mut mpsc::Sender<ChatCommand>,
+        mut after_first_piece: Option<ChatCommand>,
+    ) -> Vec<ChatEvent> {
+        let mut res = vec![];
+        loop {
+            let e = tokio::time::timeout(Duration::from_secs(10), events.sip()).await
+                .expect("The stream did not end")
+                .expect("The worker stopped");
+            if matches!(e, ChatEvent::ChatMessage(_)) {
+                if let Some(c) = after_first_piece.take() {
+                    sender.send(c).await.unwrap();
+                }
+            }
+            let ended = matches!(e, ChatEvent::StreamEnded);
+            res.push(e);
+            if ended {
+                return res;
+            }
+        }
+    }
+
+    fn answer(events: &[ChatEvent]) -> String {
+        events.iter()
+            .filter_map(|e| match e {
+                ChatEvent::ChatMessage(m) => Some(m.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn last_history(events: &[ChatEvent]) -> Vec<ChatMessage> {
+        events.iter()
+            .filter_map(|e| match e {
+                ChatEvent::HistoryUpdated(h) => Some(h.clone()),
+                _ => None,
+            })
+            .last()
+            .expect("The history was not updated")
+    }
+
+    #[tokio::test]
+    async fn prompt_is_answered() {
+        let (mut events, mut sender) = worker(mock("mock")).await;
+        sender.send(prompt("hello there")).await.unwrap();
+        let got = events_until_ended(&mut events, &mut sender, None).await;
+        assert_eq!(answer(&got), "mock heard: hello there");
+        let history = last_history(&got);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].content.as_deref(), Some("hello there"));
+        assert_eq!(history[1].content.as_deref(), Some("mock heard: hello there"));
+        assert!(!history[1].truncated);
+        assert!(!got.iter().any(|e| matches!(e, ChatEvent::ChatError(_) | ChatEvent::Truncated)));
+    }
+
+    #[tokio::test]
+    async fn stop_truncates() {
+        let (mut events, mut sender) = worker(mock("mock")).await;
+        let long = "word ".repeat(100);
+        sender.send(prompt(long.as_str())).await.unwrap();
+        let got = events_until_ended(&mut events, &mut sender, Some(ChatCommand::Stop)).await;
+        assert!(got.iter().any(|e| matches!(e, ChatEvent::Truncated)));
+        let history = last_history(&got);
+        let last = history.last().unwrap();
+        assert_eq!(last.role, ChatCompletionMessageRole::Assistant);
+        assert!(last.truncated);
+        assert!(last.content.as_ref().is_some_and(|c| c.len() < long.len()));
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_retried() {
+        let (mut events, mut sender) = worker(mock(FLAKY_MODEL)).await;
+        sender.send(prompt("again")).await.unwrap();
+        let got = events_until_ended(&mut events, &mut sender, None).await;
+        assert!(got.iter().any(|e| matches!(e, ChatEvent::Retrying { attempt: 1, .. })));
+        assert!(!got.iter().any(|e| matches!(e, ChatEvent::ChatError(_))));
+        assert_eq!(answer(&got), format!("{} heard: again", FLAKY_MODEL));
+        assert_eq!(last_history(&got).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn old_messages_are_left_out_but_not_the_system_one() {
+        // 60 tokens for the request once the answer has its reserve
+        let api = AiApi { context_length: Some(1024 + 60), ..mock("mock") };
+        let (mut events, mut sender) = worker(api).await;
+        let system = "You answer in one sentence";
+        sender.send(ChatCommand::SetContext(system.to_string())).await.unwrap();
+        let mut usage = vec![];
+        for text in ["first question", "second question", "third question", "fourth question"] {
+            sender.send(prompt(text)).await.unwrap();
+            for e in events_until_ended(&mut events, &mut sender, None).await {
+                if let ChatEvent::ContextUsage { used, left_out, .. } = e {
+                    usage.push((used, left_out));
+                }
+            }
+        }
+        assert_eq!(usage.len(), 4);
+        assert_eq!(usage[0].1, 0);
+        let (used, left_out) = *usage.last().unwrap();
+        assert!(left_out > 0 && left_out % 2 == 0, "{} messages left out", left_out);
+        assert!(used <= 60);
+        // The system message is sent with the last turn and whatever else fits
+        let last_turn = context::message_tokens(&message(ChatCompletionMessageRole::User, "fourth question"));
+        let system = context::message_tokens(&message(ChatCompletionMessageRole::System, system));
+        assert!(used >= system + last_turn);
+    }
+
+    fn split(chunks: &[&str]) -> Vec<Delta> {
+        let mut think = ThinkSplitter::default();
+        let mut res: Vec<Delta> = chunks.iter().flat_map(|c| think.feed(c)).collect();
+        res.extend(think.flush());
+        res
+    }
+
+    #[test]
+    fn think_tags_split_between_deltas() {
+        let got = split(&["<thi", "nk>Let me", " see</th", "ink>The answer", "<", "b>"]);
+        assert_eq!(got, vec![
+            Delta::Reasoning("Let me".to_string()),
+            Delta::Reasoning(" see".to_string()),
+            Delta::Content("The answer".to_string()),
+            Delta::Content("<b>".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unfinished_tag_is_content() {
+        let got = split(&["a <thi"]);
+        assert_eq!(got, vec![Delta::Content("a ".to_string()), Delta::Content("<thi".to_string())]);
+    }
+
+    #[test]
+    fn errors_sorted_by_status() {
+        let status = |code| reqwest::StatusCode::from_u16(code).unwrap();
+        assert_eq!(ChatError::from_status(status(401), None, "no".to_string()), ChatError::Auth("no".to_string()));
+        assert_eq!(ChatError::from_status(status(504), None, String::new()), ChatError::Timeout);
+        assert_eq!(ChatError::from_status(status(529), None, "busy".to_string()), ChatError::Server("busy".to_string()));
+        let e = ChatError::from_status(status(400), None, "This model's maximum context length is 8192 tokens".to_string());
+        assert!(matches!(e, ChatError::ContextLength(_)));
+        let e = ChatError::from_status(status(400), None, "bad".to_string());
+        assert_eq!(e, ChatError::Other("400 Bad Request: bad".to_string()));
+    }
+
+    #[test]
+    fn errors_sorted_by_message() {
+        assert!(matches!(ChatError::from_message("Rate limit reached".to_string()), ChatError::RateLimit { .. }));
+        assert!(matches!(ChatError::from_message("model 'x' not found".to_string()), ChatError::ModelNotFound(_)));
+        assert!(matches!(ChatError::from_message("overloaded_error: Overloaded".to_string()), ChatError::Server(_)));
+        let e = ChatError::classify(&anyhow::Error::from(ChatError::Timeout));
+        assert_eq!(e, ChatError::Timeout);
+    }
+
+    #[test]
+    fn retry_delays() {
+        let e = ChatError::Server(String::new());
+        let delays: Vec<Option<Duration>> = (0..=MAX_RETRIES).map(|a| e.retry_delay(a)).collect();
+        assert_eq!(delays, vec![
+            Some(Duration::from_millis(BACKOFF_MS)),
+            Some(Duration::from_millis(BACKOFF_MS * 2)),
+            Some(Duration::from_millis(BACKOFF_MS * 4)),
+            None,
+        ]);
+        let e = ChatError::RateLimit { retry_after: Some(Duration::from_secs(120)), message: String::new() };
+        assert_eq!(e.retry_delay(0), Some(MAX_BACKOFF));
+        assert_eq!(ChatError::Auth(String::new()).retry_delay(0), None);
+    }
+}
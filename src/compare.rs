@@ -0,0 +1,61 @@
+use iced::widget::markdown;
+use openai::chat::ChatCompletionMessageRole;
+use crate::chat::{self, ChatError, ChatMessage};
+use crate::config::AiApi;
+use crate::context;
+use crate::usage::ExchangeStats;
+
+/// Answer of one provider to the compared prompt
+#[derive(Debug)]
+pub struct Column {
+    pub api: AiApi,
+    pub answer: String,
+    pub text: markdown::Content,
+    pub stats: Option<ExchangeStats>,
+    pub error: Option<ChatError>,
+    pub done: bool,
+}
+
+impl Column {
+    pub fn new(api: AiApi) -> Self {
+        Self {
+            api,
+            answer: String::new(),
+            text: markdown::Content::new(),
+            stats: None,
+            error: None,
+            done: false,
+        }
+    }
+
+    pub fn push(&mut self, s: &str) {
+        self.answer.push_str(s);
+        self.text.push_str(s);
+    }
+
+    /// Ends the column with an error instead of an answer
+    pub fn fail(&mut self, e: ChatError) {
+        self.text.push_str(format!("\n\n**{}**", e).as_str());
+        self.error = Some(e);
+        self.done = true;
+    }
+
+    /// The answer can continue the conversation
+    pub fn can_win(&self) -> bool {
+        self.done && self.error.is_none() && !self.answer.is_empty()
+    }
+}
+
+/// The request for one provider, the oldest messages are left out when they do not fit its context
+pub fn prepare(api: &AiApi, context: &str, history: &[ChatMessage]) -> Vec<ChatMessage> {
+    let mut messages = vec![];
+    if !context.is_empty() {
+        messages.push(chat::message(ChatCompletionMessageRole::System, context));
+    }
+    let start = match context::budget(api) {
+        Some(budget) => context::first_kept(history, budget.saturating_sub(context::count(&messages))),
+        None => 0,
+    };
+    messages.extend(history[start..].iter().map(|m| m.expanded()));
+    messages
+}
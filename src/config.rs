@@ -1,6 +1,172 @@
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, BTreeMap};
 use std::fmt;
+use std::str::FromStr;
+
+crate::make_enum!(GenParam, [Temperature, TopP, MaxTokens, PresencePenalty, FrequencyPenalty, Seed, Stop]);
+
+impl GenParam {
+    pub fn label(&self) -> &str {
+        match self {
+            GenParam::Temperature => "temperature",
+            GenParam::TopP => "top_p",
+            GenParam::MaxTokens => "max_tokens",
+            GenParam::PresencePenalty => "presence_penalty",
+            GenParam::FrequencyPenalty => "frequency_penalty",
+            GenParam::Seed => "seed",
+            GenParam::Stop => "stop",
+        }
+    }
+}
+
+/// Protocol used to talk to the provider
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub enum BackendKind {
+    #[default]
+    OpenAi,
+    Ollama,
+    Anthropic,
+    Mock,
+}
+
+impl BackendKind {
+    pub const ALL: &'static [Self] = &[BackendKind::OpenAi, BackendKind::Ollama, BackendKind::Anthropic, BackendKind::Mock];
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackendKind::OpenAi => write!(f, "OpenAI compatible"),
+            BackendKind::Ollama => write!(f, "Ollama"),
+            BackendKind::Anthropic => write!(f, "Anthropic"),
+            BackendKind::Mock => write!(f, "Mock"),
+        }
+    }
+}
+
+/// What happens to the oldest messages when the conversation does not fit the context
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub enum ContextPolicy {
+    #[default]
+    DropOldest,
+    Summarise,
+}
+
+impl ContextPolicy {
+    pub const ALL: &'static [Self] = &[ContextPolicy::DropOldest, ContextPolicy::Summarise];
+}
+
+impl fmt::Display for ContextPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContextPolicy::DropOldest => write!(f, "Drop oldest"),
+            ContextPolicy::Summarise => write!(f, "Summarise"),
+        }
+    }
+}
+
+/// Settings only understood by the native Ollama API
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct OllamaOptions {
+    pub keep_alive: Option<String>,
+    pub num_ctx: Option<u32>,
+}
+
+/// Sampling parameters sent with each request, the provider defaults apply to the missing ones
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct GenerationParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+}
+
+fn parse_opt<T: FromStr>(v: &str, field: &mut Option<T>) -> bool {
+    if v.is_empty() {
+        *field = None;
+        return true;
+    }
+    match v.parse() {
+        Ok(x) => {
+            *field = Some(x);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn show_opt<T: ToString>(field: &Option<T>) -> String {
+    field.as_ref().map(|x| x.to_string()).unwrap_or_default()
+}
+
+impl GenerationParams {
+    pub fn value(&self, p: GenParam) -> String {
+        match p {
+            GenParam::Temperature => show_opt(&self.temperature),
+            GenParam::TopP => show_opt(&self.top_p),
+            GenParam::MaxTokens => show_opt(&self.max_tokens),
+            GenParam::PresencePenalty => show_opt(&self.presence_penalty),
+            GenParam::FrequencyPenalty => show_opt(&self.frequency_penalty),
+            GenParam::Seed => show_opt(&self.seed),
+            GenParam::Stop => self.stop.join(","),
+        }
+    }
+
+    /// Sets the parameter from its text form, returns false if it cannot be parsed.
+    /// An empty value goes back to the provider default.
+    pub fn set(&mut self, p: GenParam, v: &str) -> bool {
+        let v = v.trim();
+        match p {
+            GenParam::Temperature => parse_opt(v, &mut self.temperature),
+            GenParam::TopP => parse_opt(v, &mut self.top_p),
+            GenParam::MaxTokens => parse_opt(v, &mut self.max_tokens),
+            GenParam::PresencePenalty => parse_opt(v, &mut self.presence_penalty),
+            GenParam::FrequencyPenalty => parse_opt(v, &mut self.frequency_penalty),
+            GenParam::Seed => parse_opt(v, &mut self.seed),
+            GenParam::Stop => {
+                self.stop = v.split(',')
+                    .map(|s| s.to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect();
+                true
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Named system prompt, may contain variables like `{{date}}`
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq)]
+pub struct Prompt {
+    pub name: String,
+    pub text: String,
+}
+
+impl fmt::Display for Prompt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Prices in dollars per million tokens
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct AiApi {
@@ -8,6 +174,38 @@ pub struct AiApi {
     pub key: String,
     pub url: String,
     pub model: String,
+    #[serde(default)]
+    pub kind: BackendKind,
+    pub ollama: Option<OllamaOptions>,
+    pub params: Option<GenerationParams>,
+    pub pricing: Option<Pricing>,
+    /// Lets the model call the local tools, not every model supports it
+    #[serde(default)]
+    pub tools: bool,
+    /// Seconds without any data from the provider before the request fails
+    pub timeout: Option<u64>,
+    /// The model accepts images with the prompt
+    #[serde(default)]
+    pub vision: bool,
+    /// Tokens the model can take, Ollama's num_ctx is used when missing
+    pub context_length: Option<u64>,
+    #[serde(default)]
+    pub context_policy: ContextPolicy,
+    /// Text to speech voices, not chats
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub speech: bool,
+}
+
+impl AiApi {
+    /// Only chats can be sent prompts
+    pub fn is_chat(&self) -> bool {
+        !self.speech
+    }
+
+    pub fn context_limit(&self) -> Option<u64> {
+        self.context_length
+            .or_else(|| self.ollama.as_ref().and_then(|o| o.num_ctx).map(u64::from))
+    }
 }
 
 impl fmt::Display for AiApi {
@@ -16,6 +214,51 @@ impl fmt::Display for AiApi {
     }
 }
 
+/// Folder of documents the answers are grounded in
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct RagConfig {
+    /// Prompts get the passages of the documents closest to them
+    #[serde(default)]
+    pub enabled: bool,
+    pub folder: String,
+    /// OpenAI compatible API offering /embeddings, e.g. http://localhost:11434/v1 for Ollama
+    pub url: String,
+    #[serde(default)]
+    pub key: String,
+    pub model: String,
+    /// Passages added to a prompt
+    pub top_k: Option<usize>,
+}
+
+/// Sessions are named by a model after their first answer
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TitlesConfig {
+    pub enabled: bool,
+    /// Name of the chat asked for titles, the chat of the session when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat: Option<String>,
+}
+
+impl Default for TitlesConfig {
+    fn default() -> Self {
+        Self { enabled: true, chat: None }
+    }
+}
+
+/// Dictated prompts are translated before they are sent
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+pub struct TranslationConfig {
+    pub enabled: bool,
+    /// Language the transcriptions are translated to, e.g. EN
+    pub target: String,
+    /// Name of the chat asked for translations, the chat of the session when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat: Option<String>,
+    /// Whisper translates to English itself, without a chat
+    #[serde(default)]
+    pub whisper_english: bool,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct Config {
     pub ai_chats: HashMap<String, AiApi>,
@@ -30,6 +273,22 @@ pub struct Config {
     pub tr_model: String,
     pub tr_lang: String,
 
+    /// The single system prompt of older versions, moved into `prompts` on start
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub prompt_context: Option<String>,
+    #[serde(default)]
+    pub prompts: Vec<Prompt>,
+    /// Name of the prompt used when a session does not pick one
+    pub sel_prompt: Option<String>,
     pub voices: BTreeMap<String, String>,
+    pub sessions_dir: Option<String>,
+    /// Recordings are kept here as WAV files when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recordings_dir: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rag: Option<RagConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub titles: Option<TitlesConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<TranslationConfig>,
 }
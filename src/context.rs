@@ -0,0 +1,154 @@
+use openai::chat::ChatCompletionMessageRole;
+use crate::chat::ChatMessage;
+use crate::config::AiApi;
+use crate::utils::estimate_tokens;
+
+/// Role, delimiters and the like added to every message
+const MESSAGE_OVERHEAD: u64 = 4;
+/// Typical cost of an image with the common providers
+const IMAGE_TOKENS: u64 = 765;
+/// Kept free for the answer when max_tokens is not set
+const ANSWER_RESERVE: u64 = 1024;
+
+pub fn message_tokens(m: &ChatMessage) -> u64 {
+    let tool_calls: u64 = m.tool_calls.iter()
+        .flatten()
+        .map(|c| estimate_tokens(c.function.name.as_str()) + estimate_tokens(c.function.arguments.as_str()))
+        .sum();
+    MESSAGE_OVERHEAD
+        + estimate_tokens(m.content.as_deref().unwrap_or_default())
+        + m.files.iter().map(|f| f.tokens).sum::<u64>()
+        + m.images.len() as u64 * IMAGE_TOKENS
+        + tool_calls
+}
+
+pub fn count(messages: &[ChatMessage]) -> u64 {
+    messages.iter().map(message_tokens).sum()
+}
+
+/// Tokens the request may take, the rest of the context is left for the answer
+pub fn budget(api: &AiApi) -> Option<u64> {
+    let limit = api.context_limit()?;
+    let reserve = api.params.as_ref()
+        .and_then(|p| p.max_tokens)
+        .unwrap_or(ANSWER_RESERVE);
+    Some(limit.saturating_sub(reserve))
+}
+
+/// Index of the first message to send so that the rest fits the budget.
+/// The history is only cut before a user message, so tool calls stay with their results,
+/// and the last turn is always kept.
+pub fn first_kept(history: &[ChatMessage], budget: u64) -> usize {
+    let mut used = 0;
+    let mut start = history.len();
+    for (i, m) in history.iter().enumerate().rev() {
+        used += message_tokens(m);
+        if m.role != ChatCompletionMessageRole::User {
+            continue;
+        }
+        if used > budget && start < history.len() {
+            break;
+        }
+        start = i;
+    }
+    start
+}
+
+/// The messages as plain text, given to the model to summarise
+pub fn transcript(messages: &[ChatMessage]) -> String {
+    let mut res = String::new();
+    for m in messages {
+        let who = match m.role {
+            ChatCompletionMessageRole::User => "User",
+            ChatCompletionMessageRole::Assistant => "Assistant",
+            ChatCompletionMessageRole::Tool => "Tool result",
+            _ => continue,
+        };
+        let content = m.content.as_deref().unwrap_or_default();
+        if content.is_empty() {
+            continue;
+        }
+        res.push_str(format!("{}: {}\n\n", who, content).as_str());
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::message;
+    use crate::config::GenerationParams;
+
+    fn m(role: ChatCompletionMessageRole, text: &str) -> ChatMessage {
+        message(role, text)
+    }
+
+    fn user(text: &str) -> ChatMessage {
+        m(ChatCompletionMessageRole::User, text)
+    }
+
+    fn assistant(text: &str) -> ChatMessage {
+        m(ChatCompletionMessageRole::Assistant, text)
+    }
+
+    #[test]
+    fn budget_leaves_room_for_the_answer() {
+        let api = AiApi { context_length: Some(8192), ..AiApi::default() };
+        assert_eq!(budget(&api), Some(8192 - ANSWER_RESERVE));
+        let params = GenerationParams { max_tokens: Some(2000), ..GenerationParams::default() };
+        let api = AiApi { params: Some(params), ..api };
+        assert_eq!(budget(&api), Some(6192));
+        let api = AiApi { context_length: Some(100), ..AiApi::default() };
+        assert_eq!(budget(&api), Some(0));
+        assert_eq!(budget(&AiApi::default()), None);
+    }
+
+    #[test]
+    fn everything_kept_when_it_fits() {
+        let history = vec![user("q1"), assistant("a1"), user("q2"), assistant("a2")];
+        assert_eq!(first_kept(&history, count(&history)), 0);
+        assert_eq!(first_kept(&[], 0), 0);
+    }
+
+    #[test]
+    fn last_turn_kept_over_budget() {
+        let long = "word ".repeat(500);
+        let history = vec![user("q1"), assistant("a1"), user(long.as_str()), assistant(long.as_str())];
+        assert!(count(&history[2..]) > 10);
+        assert_eq!(first_kept(&history, 10), 2);
+        assert_eq!(first_kept(&history[2..], 0), 0);
+    }
+
+    #[test]
+    fn tool_calls_stay_with_their_results() {
+        let mut call = assistant("");
+        call.tool_calls = Some(vec![]);
+        let history = vec![
+            user("q1"), assistant("a1"),
+            user("What is 2+2?"), call, m(ChatCompletionMessageRole::Tool, "4"), assistant("It is 4"),
+            user("q3"), assistant("a3"),
+        ];
+        // Enough for the tool result and the last turn but not for the call
+        let budget = count(&history[4..]);
+        assert_eq!(first_kept(&history, budget), 6);
+        // Enough for the whole exchange with the tool
+        let budget = count(&history[2..]);
+        assert_eq!(first_kept(&history, budget), 2);
+        for b in 0..count(&history) {
+            let start = first_kept(&history, b);
+            assert_eq!(history[start].role, ChatCompletionMessageRole::User, "budget {}", b);
+        }
+    }
+
+    #[test]
+    fn transcript_has_the_conversation_only() {
+        let history = vec![
+            m(ChatCompletionMessageRole::System, "Be brief"),
+            user("2+2?"),
+            assistant(""),
+            m(ChatCompletionMessageRole::Tool, "4"),
+            assistant("It is 4"),
+        ];
+        assert_eq!(transcript(&history), "User: 2+2?\n\nTool result: 4\n\nAssistant: It is 4\n\n");
+    }
+}
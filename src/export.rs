@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use openai::chat::ChatCompletionMessageRole;
+use pulldown_cmark::{CowStr, Event, Options, Parser, Tag};
+use anyhow::Result;
+use crate::chat::ChatMessage;
+use crate::config::{AiApi, BackendKind, GenerationParams, Prompt};
+use crate::session::Session;
+use crate::usage::UsageTotals;
+
+crate::make_enum!(ExportFormat, [Markdown, Json, Html]);
+
+impl ExportFormat {
+    pub fn extension(&self) -> &str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Html => "html",
+        }
+    }
+
+    /// Format chosen by the extension of the file, Markdown when it is not known
+    pub fn from_path(path: &Path) -> Self {
+        let ext = path.extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        ExportFormat::ALL.iter()
+            .copied()
+            .find(|f| f.extension() == ext || (ext == "htm" && *f == ExportFormat::Html))
+            .unwrap_or_default()
+    }
+}
+
+const HTML_STYLE: &str = "body { font-family: sans-serif; max-width: 50em; margin: 2em auto; padding: 0 1em; line-height: 1.5; color: #222; }
+h1 { font-size: 1.6em; }
+.meta { color: #777; font-size: 0.9em; }
+.message { border-radius: 6px; padding: 0.5em 1em; margin: 1em 0; }
+.user { background: #eef3fb; }
+.assistant { background: #f6f6f6; }
+.tool { background: #fbf6ea; font-size: 0.9em; }
+.role { font-weight: bold; margin-bottom: 0.3em; }
+pre { background: #1e1e2e; color: #cdd6f4; padding: 0.8em; border-radius: 6px; overflow-x: auto; }
+code { font-family: monospace; font-size: 0.95em; }
+:not(pre) > code { background: #e8e8e8; padding: 0.1em 0.3em; border-radius: 3px; }
+blockquote { border-left: 3px solid #ccc; margin-left: 0; padding-left: 1em; color: #555; }
+table { border-collapse: collapse; }
+td, th { border: 1px solid #ccc; padding: 0.3em 0.6em; }";
+
+fn role_name(role: &ChatCompletionMessageRole) -> &'static str {
+    match role {
+        ChatCompletionMessageRole::System => "System",
+        ChatCompletionMessageRole::User => "You",
+        ChatCompletionMessageRole::Assistant => "Assistant",
+        ChatCompletionMessageRole::Tool => "Tool",
+        _ => "Function",
+    }
+}
+
+/// Lines telling what was sent besides the text: tool calls, files, images and the recording
+fn extras(m: &ChatMessage) -> Vec<String> {
+    let mut res = vec![];
+    if m.truncated {
+        res.push("Stopped before the end".to_string());
+    }
+    for c in m.tool_calls.iter().flatten() {
+        res.push(format!("Tool call: `{}({})`", c.function.name, c.function.arguments));
+    }
+    if !m.files.is_empty() {
+        let names: Vec<&str> = m.files.iter().map(|f| f.name.as_str()).collect();
+        res.push(format!("Files: {}", names.join(", ")));
+    }
+    if !m.images.is_empty() {
+        res.push(format!("{} image(s)", m.images.len()));
+    }
+    if let Some(t) = m.transcription.as_ref() {
+        res.push(format!("Transcription: {}", t.text.trim()));
+        if let Some(audio) = t.audio.as_ref() {
+            res.push(format!("Audio: {}", audio));
+        }
+    }
+    res
+}
+
+/// The session with a heading for each message
+pub fn markdown(session: &Session) -> String {
+    let mut res = format!("# {}\n\n", title(session));
+    res.push_str(format!("*{}, {}*\n\n", session.provider, session.created.format("%Y-%m-%d %H:%M UTC")).as_str());
+    for m in session.messages.iter() {
+        let content = m.content.as_deref().unwrap_or_default();
+        let extras = extras(m);
+        if content.is_empty() && extras.is_empty() {
+            continue;
+        }
+        res.push_str(format!("## {}\n\n", role_name(&m.role)).as_str());
+        for e in extras {
+            res.push_str(format!("> {}\n", e).as_str());
+        }
+        res.push_str(format!("\n{}\n\n", content.trim_end()).as_str());
+    }
+    res
+}
+
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    id: &'a str,
+    title: &'a str,
+    provider: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<BackendKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<&'a GenerationParams>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_name: Option<&'a str>,
+    /// Text of the prompt with its variables, they are filled in when a prompt is sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_prompt: Option<&'a str>,
+    created: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    exported: DateTime<Utc>,
+    usage: &'a UsageTotals,
+    messages: &'a [ChatMessage],
+}
+
+/// The session with the provider settings and system prompt it was held with, without the API key
+pub fn json(session: &Session, api: Option<&AiApi>, prompt: Option<&Prompt>) -> Result<String> {
+    let export = JsonExport {
+        id: session.id.as_str(),
+        title: session.title.as_str(),
+        provider: session.provider.as_str(),
+        kind: api.map(|a| a.kind),
+        model: api.map(|a| a.model.as_str()),
+        parameters: api.and_then(|a| a.params.as_ref()),
+        prompt_name: prompt.map(|p| p.name.as_str()),
+        system_prompt: prompt.map(|p| p.text.as_str()),
+        created: session.created,
+        updated: session.updated,
+        exported: Utc::now(),
+        usage: &session.usage,
+        messages: &session.messages,
+    };
+    Ok(serde_json::to_string_pretty(&export)?)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Links and images to http(s), mailto or relative addresses, others like `javascript:` go nowhere
+fn safe_url(url: CowStr) -> CowStr {
+    // Browsers skip blanks and control characters in the scheme
+    let cleaned: String = url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_control()).collect();
+    let end = cleaned.find(['/', '?', '#']).unwrap_or(cleaned.len());
+    let Some((scheme, _)) = cleaned[..end].split_once(':') else {
+        return url;
+    };
+    if ["http", "https", "mailto"].iter().any(|s| scheme.eq_ignore_ascii_case(s)) {
+        url
+    } else {
+        CowStr::Borrowed("#")
+    }
+}
+
+/// Markdown as HTML, HTML written in the messages is shown as text
+fn to_html(md: &str) -> String {
+    let parser = Parser::new_ext(md, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH)
+        .map(|e| match e {
+            Event::Html(s) | Event::InlineHtml(s) => Event::Text(s),
+            Event::Start(Tag::Link { link_type, dest_url, title, id }) =>
+                Event::Start(Tag::Link { link_type, dest_url: safe_url(dest_url), title, id }),
+            Event::Start(Tag::Image { link_type, dest_url, title, id }) =>
+                Event::Start(Tag::Image { link_type, dest_url: safe_url(dest_url), title, id }),
+            e => e,
+        });
+    let mut res = String::new();
+    pulldown_cmark::html::push_html(&mut res, parser);
+    res
+}
+
+/// Single HTML file with its styles, readable without the app
+pub fn html(session: &Session) -> String {
+    let title = escape(title(session).as_str());
+    let mut res = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>\n{}\n</style>\n</head>\n<body>\n", title, HTML_STYLE);
+    res.push_str(format!("<h1>{}</h1>\n", title).as_str());
+    res.push_str(format!("<p class=\"meta\">{}, {}</p>\n", escape(session.provider.as_str()), session.created.format("%Y-%m-%d %H:%M UTC")).as_str());
+    for m in session.messages.iter() {
+        let content = m.content.as_deref().unwrap_or_default();
+        let extras = extras(m);
+        if content.is_empty() && extras.is_empty() {
+            continue;
+        }
+        let class = role_name(&m.role).to_lowercase();
+        let class = if class == "you" { "user".to_string() } else { class };
+        res.push_str(format!("<div class=\"message {}\">\n<div class=\"role\">{}</div>\n", class, role_name(&m.role)).as_str());
+        for e in extras {
+            res.push_str(format!("<p class=\"meta\">{}</p>\n", escape(e.as_str())).as_str());
+        }
+        res.push_str(to_html(content).as_str());
+        res.push_str("</div>\n");
+    }
+    res.push_str("</body>\n</html>\n");
+    res
+}
+
+fn title(session: &Session) -> String {
+    if session.title.is_empty() {
+        "Untitled".to_string()
+    } else {
+        session.title.clone()
+    }
+}
+
+/// Name offered for the file, without characters that are not allowed in file names
+pub fn file_name(session: &Session, format: ExportFormat) -> String {
+    let name: String = title(session).chars()
+        .map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}.{}", name.trim(), format.extension())
+}
+
+pub fn export(session: &Session, api: Option<&AiApi>, prompt: Option<&Prompt>, format: ExportFormat) -> Result<String> {
+    match format {
+        ExportFormat::Markdown => Ok(markdown(session)),
+        ExportFormat::Json => json(session, api, prompt),
+        ExportFormat::Html => Ok(html(session)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::message;
+
+    fn session(title: &str, messages: Vec<ChatMessage>) -> Session {
+        let mut s = Session::new("Local");
+        s.title = title.to_string();
+        s.messages = messages;
+        s
+    }
+
+    #[test]
+    fn markdown_has_a_heading_per_message() {
+        let mut answer = message(ChatCompletionMessageRole::Assistant, "Paris.\n\n");
+        answer.truncated = true;
+        let s = session("Capitals", vec![
+            message(ChatCompletionMessageRole::User, "Capital of France?"),
+            message(ChatCompletionMessageRole::Assistant, ""),
+            answer,
+        ]);
+        let md = markdown(&s);
+        assert!(md.starts_with("# Capitals\n\n*Local, "));
+        assert!(md.contains("## You\n\n\nCapital of France?\n\n"));
+        assert!(md.ends_with("## Assistant\n\n> Stopped before the end\n\nParis.\n\n"));
+        // The empty answer is left out
+        assert_eq!(md.matches("## Assistant").count(), 1);
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let s = session("<script>alert(1)</script>", vec![
+            message(ChatCompletionMessageRole::User, "Look <img src=x onerror=alert(1)> & more"),
+        ]);
+        let page = html(&s);
+        assert!(page.contains("<title>&lt;script&gt;alert(1)&lt;/script&gt;</title>"));
+        assert!(!page.contains("<img src=x"));
+        assert!(page.contains("&lt;img src=x onerror=alert(1)&gt;"));
+        assert!(page.contains("&amp; more"));
+    }
+
+    #[test]
+    fn html_links_only_to_safe_addresses() {
+        let page = to_html("[a](javascript:alert(1)) [b]( JavaScript:alert(1)) ![c](data:text/html,x) [d](https://example.com/a:b) [e](mailto:me@example.com) [f](notes/a.md)");
+        assert!(!page.to_lowercase().contains("javascript:"));
+        assert!(!page.contains("data:"));
+        assert!(page.contains("href=\"https://example.com/a:b\""));
+        assert!(page.contains("href=\"mailto:me@example.com\""));
+        assert!(page.contains("href=\"notes/a.md\""));
+        assert_eq!(page.matches("\"#\"").count(), 3);
+    }
+
+    #[test]
+    fn file_names_are_safe() {
+        let s = session("What is 1/2: a \"half\"?", vec![]);
+        assert_eq!(file_name(&s, ExportFormat::Html), "What is 1_2_ a _half__.html");
+        assert_eq!(file_name(&session("", vec![]), ExportFormat::Json), "Untitled.json");
+        assert_eq!(file_name(&session(" ../x ", vec![]), ExportFormat::Markdown), "___x.md");
+    }
+
+    #[test]
+    fn json_has_the_prompt_text() {
+        let prompt = Prompt { name: "Terse".to_string(), text: "Answer briefly, today is {{date}}".to_string() };
+        let s = session("t", vec![message(ChatCompletionMessageRole::User, "hi")]);
+        let v: serde_json::Value = serde_json::from_str(json(&s, None, Some(&prompt)).unwrap().as_str()).unwrap();
+        assert_eq!(v["prompt_name"], "Terse");
+        assert_eq!(v["system_prompt"], "Answer briefly, today is {{date}}");
+        assert!(v.get("model").is_none());
+    }
+}
@@ -0,0 +1,347 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use openai::chat::ChatCompletionMessageRole;
+use anyhow::{Result, bail};
+use tracing::{debug, info};
+use crate::chat::{self, ChatMessage};
+use crate::session::{Node, Session, SessionStore};
+
+/// Message of an imported conversation with its place in the tree
+struct Entry {
+    id: String,
+    parent: Option<String>,
+    /// None for the entries that are only kept for the structure, like ChatGPT's hidden system messages
+    message: Option<ChatMessage>,
+    time: f64,
+}
+
+fn time(secs: f64) -> Option<DateTime<Utc>> {
+    // Some exports count in milliseconds
+    let secs = if secs > 1e11 { secs / 1000.0 } else { secs };
+    DateTime::from_timestamp(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+}
+
+fn role(s: &str) -> Option<ChatCompletionMessageRole> {
+    match s {
+        "user" => Some(ChatCompletionMessageRole::User),
+        "assistant" => Some(ChatCompletionMessageRole::Assistant),
+        // System and tool messages of other tools cannot be sent again as they are
+        _ => None,
+    }
+}
+
+fn message(role: ChatCompletionMessageRole, content: String, secs: f64) -> ChatMessage {
+    let mut m = chat::message(role, content);
+    m.time = time(secs);
+    m
+}
+
+/// Builds the tree of the session from the entries, `current` is the last message of the branch shown
+fn fill(session: &mut Session, entries: Vec<Entry>, current: Option<&str>) {
+    let by_id: HashMap<&str, &Entry> = entries.iter().map(|e| (e.id.as_str(), e)).collect();
+    let mut kept: Vec<&Entry> = entries.iter().filter(|e| e.message.is_some()).collect();
+    // The tree takes the latest alternative as the one to continue with
+    kept.sort_by(|a, b| a.time.total_cmp(&b.time));
+    let index: HashMap<&str, usize> = kept.iter()
+        .enumerate()
+        .map(|(i, e)| (e.id.as_str(), i))
+        .collect();
+
+    // Nearest ancestor that was kept, entries left out are skipped over
+    let kept_ancestor = |mut parent: Option<&str>| -> Option<usize> {
+        for _ in 0..entries.len() {
+            let p = parent?;
+            if let Some(i) = index.get(p) {
+                return Some(*i);
+            }
+            parent = by_id.get(p).and_then(|e| e.parent.as_deref());
+        }
+        None
+    };
+
+    session.tree = kept.iter()
+        .map(|e| Node {
+            parent: kept_ancestor(e.parent.as_deref()),
+            message: e.message.clone().unwrap_or_else(|| chat::message(ChatCompletionMessageRole::User, "")),
+        })
+        .collect();
+
+    let mut last = current
+        .and_then(|c| index.get(c).copied().or_else(|| kept_ancestor(Some(c))))
+        .or_else(|| session.tree.len().checked_sub(1));
+    let mut path = vec![];
+    while let Some(i) = last {
+        path.push(i);
+        last = session.tree[i].parent;
+        if path.len() > session.tree.len() {
+            break;
+        }
+    }
+    path.reverse();
+    session.messages = path.iter().map(|i| session.tree[*i].message.clone()).collect();
+    session.path = path;
+}
+
+/// Id of the session made from the id in the export, which could otherwise name a file outside the sessions folder
+fn session_id(tool: &str, id: &str) -> String {
+    let id: String = id.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    format!("{}-{}", tool, id)
+}
+
+fn new_session(id: String, title: Option<String>, provider: String, created: Option<f64>, updated: Option<f64>) -> Session {
+    let mut s = Session::new(provider);
+    s.id = id;
+    s.title = title.unwrap_or_default();
+    // The title of the other tool is kept instead of asking for one
+    s.titled = !s.title.is_empty();
+    if let Some(t) = created.and_then(time) {
+        s.created = t;
+    }
+    s.updated = updated.and_then(time).unwrap_or(s.created);
+    s
+}
+
+#[derive(Deserialize)]
+struct GptConversation {
+    id: Option<String>,
+    conversation_id: Option<String>,
+    title: Option<String>,
+    create_time: Option<f64>,
+    update_time: Option<f64>,
+    mapping: HashMap<String, GptNode>,
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GptNode {
+    id: String,
+    message: Option<GptMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GptMessage {
+    author: GptAuthor,
+    create_time: Option<f64>,
+    content: Option<GptContent>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+struct GptAuthor {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct GptContent {
+    #[serde(default)]
+    parts: Vec<Value>,
+    text: Option<String>,
+}
+
+impl GptContent {
+    /// The text parts, images and other attachments are left out
+    fn text(&self) -> String {
+        let parts: Vec<&str> = self.parts.iter().filter_map(|p| p.as_str()).collect();
+        if parts.is_empty() {
+            return self.text.clone().unwrap_or_default();
+        }
+        parts.join("\n")
+    }
+}
+
+fn chatgpt(c: GptConversation) -> Session {
+    let model = c.mapping.values()
+        .filter_map(|n| n.message.as_ref())
+        .find_map(|m| m.metadata.get("model_slug").and_then(|v| v.as_str()).map(|s| s.to_string()));
+    let provider = model.map(|m| format!("ChatGPT [{}]", m)).unwrap_or_else(|| "ChatGPT".to_string());
+    let id = c.id.clone().or(c.conversation_id.clone()).unwrap_or_else(|| uuid::Uuid::now_v1(&[21, 2, 31, 52, 0, 61]).to_string());
+    let mut session = new_session(session_id("chatgpt", id.as_str()), c.title, provider, c.create_time, c.update_time);
+    let entries = c.mapping.into_values()
+        .map(|n| {
+            let time = n.message.as_ref().and_then(|m| m.create_time).unwrap_or_default();
+            let message = n.message.and_then(|m| {
+                let text = m.content.map(|c| c.text()).unwrap_or_default();
+                let role = role(m.author.role.as_str())?;
+                (!text.trim().is_empty()).then(|| message(role, text, time))
+            });
+            Entry { id: n.id, parent: n.parent, message, time }
+        })
+        .collect();
+    fill(&mut session, entries, c.current_node.as_deref());
+    session
+}
+
+#[derive(Deserialize)]
+struct WebUiExport {
+    id: String,
+    title: Option<String>,
+    created_at: Option<f64>,
+    updated_at: Option<f64>,
+    chat: WebUiChat,
+}
+
+#[derive(Deserialize)]
+struct WebUiChat {
+    title: Option<String>,
+    #[serde(default)]
+    models: Vec<String>,
+    history: WebUiHistory,
+}
+
+#[derive(Deserialize)]
+struct WebUiHistory {
+    #[serde(default)]
+    messages: HashMap<String, WebUiMessage>,
+    #[serde(rename = "currentId")]
+    current_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebUiMessage {
+    id: String,
+    #[serde(rename = "parentId")]
+    parent_id: Option<String>,
+    role: String,
+    #[serde(default)]
+    content: String,
+    timestamp: Option<f64>,
+}
+
+fn open_webui(c: WebUiExport) -> Session {
+    let provider = c.chat.models.first()
+        .map(|m| format!("Open WebUI [{}]", m))
+        .unwrap_or_else(|| "Open WebUI".to_string());
+    let title = c.title.or(c.chat.title);
+    let mut session = new_session(session_id("openwebui", c.id.as_str()), title, provider, c.created_at, c.updated_at);
+    let entries = c.chat.history.messages.into_values()
+        .map(|m| {
+            let time = m.timestamp.unwrap_or_default();
+            let message = role(m.role.as_str())
+                .filter(|_| !m.content.trim().is_empty())
+                .map(|r| message(r, m.content, time));
+            Entry { id: m.id, parent: m.parent_id, message, time }
+        })
+        .collect();
+    fill(&mut session, entries, c.chat.history.current_id.as_deref());
+    session
+}
+
+/// Reads a ChatGPT `conversations.json` or an Open WebUI export, a single conversation or a list of them
+pub fn parse(s: &str) -> Result<Vec<Session>> {
+    let v: Value = serde_json::from_str(s)?;
+    let items = match v {
+        Value::Array(a) => a,
+        v => vec![v],
+    };
+    let mut res = vec![];
+    for item in items {
+        if item.get("mapping").is_some() {
+            res.push(chatgpt(serde_json::from_value(item)?));
+        } else if item.get("chat").is_some_and(|c| c.get("history").is_some()) {
+            res.push(open_webui(serde_json::from_value(item)?));
+        } else {
+            bail!("Not a ChatGPT or Open WebUI export");
+        }
+    }
+    Ok(res)
+}
+
+/// Stores the conversations of the file as sessions, importing a file again replaces them
+pub async fn import(path: &Path, store: &SessionStore) -> Result<usize> {
+    let s = tokio::fs::read_to_string(path).await?;
+    let sessions = parse(s.as_str())?;
+    for session in sessions.iter().filter(|s| !s.is_empty()) {
+        debug!("Importing {}: {} messages", session.title, session.tree.len());
+        store.save(session)?;
+    }
+    let n = sessions.iter().filter(|s| !s.is_empty()).count();
+    info!("Imported {} conversations from {}", n, path.display());
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hidden root and system message, the second answer to the prompt is the one shown
+    const CHATGPT: &str = r#"{
+        "id": "c1", "title": "Greeting", "create_time": 1700000000.0, "update_time": 1700000100.0,
+        "current_node": "a2",
+        "mapping": {
+            "root": { "id": "root", "message": null, "parent": null },
+            "sys": { "id": "sys", "parent": "root", "message": {
+                "author": { "role": "system" }, "create_time": null, "content": { "parts": [""] } } },
+            "u1": { "id": "u1", "parent": "sys", "message": {
+                "author": { "role": "user" }, "create_time": 1700000001.0, "content": { "parts": ["Hi"] } } },
+            "a1": { "id": "a1", "parent": "u1", "message": {
+                "author": { "role": "assistant" }, "create_time": 1700000002.0, "content": { "parts": ["Hello"] },
+                "metadata": { "model_slug": "gpt-4o" } } },
+            "a2": { "id": "a2", "parent": "u1", "message": {
+                "author": { "role": "assistant" }, "create_time": 1700000003.0, "content": { "parts": ["Hey there"] } } }
+        }
+    }"#;
+
+    /// The current message is the older of the two answers
+    const WEBUI: &str = r#"{
+        "id": "w1", "title": "Plan", "created_at": 1700000000, "updated_at": 1700000100,
+        "chat": { "models": ["llama3"], "history": { "currentId": "a1", "messages": {
+            "u1": { "id": "u1", "parentId": null, "role": "user", "content": "Plan a trip", "timestamp": 1700000001 },
+            "a1": { "id": "a1", "parentId": "u1", "role": "assistant", "content": "Go north", "timestamp": 1700000002 },
+            "a2": { "id": "a2", "parentId": "u1", "role": "assistant", "content": "Go south", "timestamp": 1700000003 }
+        } } }
+    }"#;
+
+    fn texts(s: &Session) -> Vec<&str> {
+        s.messages.iter().map(|m| m.content.as_deref().unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn chatgpt_branches() {
+        let res = parse(CHATGPT).unwrap();
+        let s = &res[0];
+        assert_eq!(s.id, "chatgpt-c1");
+        assert_eq!(s.title, "Greeting");
+        assert!(s.titled);
+        assert_eq!(s.provider, "ChatGPT [gpt-4o]");
+        assert_eq!(s.tree.len(), 3);
+        assert_eq!(texts(s), vec!["Hi", "Hey there"]);
+        assert_eq!(s.siblings(1), (1, 2));
+        assert_eq!(s.tree[s.path[0]].parent, None);
+    }
+
+    #[test]
+    fn open_webui_side_branch() {
+        let res = parse(WEBUI).unwrap();
+        let s = &res[0];
+        assert_eq!(s.id, "openwebui-w1");
+        assert_eq!(s.provider, "Open WebUI [llama3]");
+        assert_eq!(texts(s), vec!["Plan a trip", "Go north"]);
+        assert_eq!(s.siblings(1), (0, 2));
+    }
+
+    #[test]
+    fn list_or_single() {
+        assert_eq!(parse(format!("[{}, {}]", CHATGPT, WEBUI).as_str()).unwrap().len(), 2);
+        assert_eq!(parse(WEBUI).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unknown_shape() {
+        assert!(parse(r#"{ "conversation": [] }"#).is_err());
+        assert!(parse(r#"[{ "mapping": 1 }]"#).is_err());
+        assert!(parse("not json").is_err());
+    }
+
+    #[test]
+    fn ids_stay_in_the_folder() {
+        let res = parse(CHATGPT.replace(r#""id": "c1""#, r#""id": "../../etc/c1""#).as_str()).unwrap();
+        assert!(!res[0].id.contains('/') && !res[0].id.contains(".."), "{}", res[0].id);
+    }
+}
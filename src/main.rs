@@ -2,20 +2,22 @@
 
 use iced::widget::{button, column, row, text_editor, Button,
 text, combo_box, ComboBox, checkbox, container,
-text_input, TextInput, scrollable
+text_input, TextInput, scrollable, image, progress_bar
 };
 use iced::{Element, Subscription, Theme};
+use openai::chat::ChatCompletionMessageRole;
 use tokio::sync::OnceCell;
 use tracing::{debug, error, info};
 use tokio::sync::RwLock;
 use iced::widget::markdown;
 use pv_recorder::PvRecorderBuilder;
 use std::sync::Arc;
+use std::path::PathBuf;
 use iced::futures::channel::mpsc;
 use iced::futures::sink::SinkExt;
 use iced::task::{Never, Sipper, sipper};
 use whisper_rs;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use webbrowser;
 use uuid;
 
@@ -25,6 +27,20 @@ mod utils;
 mod transcribe;
 mod chat;
 mod voice;
+mod session;
+mod backend;
+mod usage;
+mod tools;
+mod prompts;
+mod attachments;
+mod context;
+mod compare;
+mod rag;
+mod export;
+mod import;
+mod search;
+mod titles;
+mod translate;
 
 use vumeter::VUMeter;
 use config::Config;
@@ -32,6 +48,11 @@ use voice::{VoiceCommand, VoiceEvent};
 
 const CHUNK: i32 = 1024;
 const CONFIG: &str = "app.toml";
+const SESSIONS_DIR: &str = "sessions";
+const USAGE_FILE: &str = "usage.json";
+const NO_VISION: &str = "The selected chat does not accept images. Mark it with Accepts images in Settings if its model supports them, or pick another chat.";
+/// Characters of a message shown in the list of messages
+const PREVIEW_LEN: usize = 80;
 const DEFAULT_VOICE: &str = "pFZP5JQG7iQjIQuC4Bku";
 const MAX_AMPLITUDE_F32: f32 = (u16::MAX / 2) as f32;
 
@@ -39,6 +60,26 @@ static DEFAULT_DEVICE: OnceCell<i32> = OnceCell::const_new();
 
 make_enum!(Language, [EN,PL,DE,CN,FR,IT,ES,PT,RU,UA,JP,TR]);
 
+impl Language {
+    /// Name of the language for the translation prompt
+    fn name(&self) -> &'static str {
+        match self {
+            Language::EN => "English",
+            Language::PL => "Polish",
+            Language::DE => "German",
+            Language::CN => "Chinese",
+            Language::FR => "French",
+            Language::IT => "Italian",
+            Language::ES => "Spanish",
+            Language::PT => "Portuguese",
+            Language::RU => "Russian",
+            Language::UA => "Ukrainian",
+            Language::JP => "Japanese",
+            Language::TR => "Turkish",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 enum Message {
     EditAction(text_editor::Action),
@@ -48,6 +89,7 @@ enum Message {
     AppendResult(String),
     ToggleSettings,
     SaveSettings,
+    ModelValidated(Result<(), String>),
     PlayToggle(bool),
     Void,
     FontSizeChangedUp,
@@ -59,15 +101,101 @@ enum Message {
     LanguageSelected(Language),
     ShowError(String),
     HideModal,
-    CtxInput(String),
+    PromptPicked(String),
+    NewPrompt,
+    DeletePrompt,
+    PromptNameChanged(String),
+    PromptEditAction(text_editor::Action),
+    DefaultPromptSelected(String),
+    SessionPromptSelected(String),
     //ChatSelected(String),
     ChatSelected(config::AiApi),
     ChatEventReceived(chat::ChatEvent),
     AskChat,
+    AskWithClipboard(Option<String>),
+    StopChat,
+    ToggleReasoning,
+    NewConversation,
     ChatApiKeyChanged(String),
     ChatApiUrlChanged(String),
     //ChatApiNameChanged(String),
     ChatApiModelChanged(String),
+    ChatApiKindChanged(config::BackendKind),
+    GenParamChanged(config::GenParam, String),
+    PriceInChanged(String),
+    PriceOutChanged(String),
+    ToggleUsage,
+    FetchModels,
+    ModelsFetched(Result<Vec<String>, String>),
+    ModelSelected(String),
+    OllamaPullInput(String),
+    OllamaPull,
+    OllamaPullProgress(backend::ollama::PullProgress),
+    OllamaPullDone(Result<(), String>),
+    OllamaDelete,
+    OllamaDeleted(Result<(), String>),
+    OllamaKeepAliveChanged(String),
+    OllamaNumCtxChanged(String),
+    TimeoutChanged(String),
+    ContextLengthChanged(String),
+    ContextPolicyChanged(config::ContextPolicy),
+    ToolsToggle(bool),
+    VisionToggle(bool),
+    AttachImage,
+    PasteImage,
+    FileDropped(PathBuf),
+    ImagesAttached(Result<Vec<attachments::ImageAttachment>, String>),
+    RemoveImage(usize),
+    AttachFile,
+    FilesAttached(Result<Vec<attachments::FileAttachment>, String>),
+    RemoveFile(usize),
+    ToolDecision(bool),
+    ToolAlwaysAllow,
+    CompareToggle(bool),
+    CompareChatToggle(String, bool),
+    CompareChunk(usize, String),
+    CompareDone(usize, Result<usage::ExchangeStats, chat::ChatError>),
+    PickWinner(usize),
+    Regenerate(usize),
+    EditMessage(usize),
+    CancelEdit,
+    DeleteMessage(usize),
+    SwitchBranch(usize, bool),
+    RagToggle(bool),
+    RagFolderChanged(String),
+    RagBrowse,
+    RagUrlChanged(String),
+    RagKeyChanged(String),
+    RagModelChanged(String),
+    RagTopKChanged(String),
+    RagIndex,
+    RagProgress(String),
+    RagIndexed(Result<rag::Index, String>),
+    Transcribed(Result<transcribe::Transcription, String>),
+    RecordingsDirChanged(String),
+    Export,
+    Exported(Result<String, String>),
+    Import,
+    Imported(Result<usize, String>),
+    ToggleSearch,
+    SearchInput(String),
+    SearchProvider(String),
+    SearchAnyProvider,
+    SearchRole(search::RoleFilter),
+    SearchFromChanged(String),
+    SearchToChanged(String),
+    OpenSearchHit(String, usize),
+    TitlesToggle(bool),
+    TitleChatSelected(config::AiApi),
+    TitleChatOfSession,
+    Titled(String, Result<titles::Titled, chat::ChatError>),
+    RenameTagsInput(String),
+    TranslateToggle(bool),
+    TranslateTargetSelected(Language),
+    TranslateChatSelected(config::AiApi),
+    TranslateChatOfSession,
+    WhisperEnglishToggle(bool),
+    Translated(Result<translate::Translated, chat::ChatError>),
     CopyResult,
     VoiceEventRec(VoiceEvent),
     CopyCode,
@@ -75,6 +203,11 @@ enum Message {
     TrModeToggle(bool),
     NewAiChat,
     NewAiChatNameChanged(String),
+    OpenSession(String),
+    StartRename(String),
+    RenameInput(String),
+    RenameSubmit,
+    DeleteSession(String),
 }
 
 #[derive(Debug, Clone)]
@@ -100,6 +233,9 @@ struct AiVChat {
     query_text: text_editor::Content,
     result_text: markdown::Content,
     result_raw: Vec<String>,
+    reasoning_text: markdown::Content,
+    reasoning_raw: String,
+    show_reasoning: bool,
     themes: combo_box::State<Theme>,
     theme: Option<Theme>,
     play: bool,
@@ -124,7 +260,12 @@ struct AiVChat {
     show_modal: bool,
     modal_text: String,
 
-    prompt_context: String,
+    prompts: Vec<config::Prompt>,
+    s_prompts: combo_box::State<String>,
+    // Prompt of the library edited in the settings
+    prompt_edit: Option<usize>,
+    prompt_editor: text_editor::Content,
+    default_prompt: Option<String>,
     /*ai_chat: Option<String>,
     ai_chats: combo_box::State<String>,
     ai_api: config::AiApi,
@@ -132,10 +273,19 @@ struct AiVChat {
     ai_api_k: String,*/
     ai_editor_dirty: bool,
     ai_cmd: Option<mpsc::Sender<chat::ChatCommand>>,
+    streaming: bool,
 
     s_ai_chat: Option<config::AiApi>,
     s_ai_chats: combo_box::State<config::AiApi>,
     s_ai_table: HashMap<String, config::AiApi>,
+    s_kinds: combo_box::State<config::BackendKind>,
+    s_models: combo_box::State<String>,
+    // Text of the generation parameters as typed, in the order of GenParam::ALL
+    gen_inputs: Vec<String>,
+    // Input and output price per million tokens as typed
+    price_inputs: [String; 2],
+    ollama_pull: String,
+    ollama_progress: String,
 
     v_sender: Option<mpsc::Sender<VoiceCommand>>,
     voices: BTreeMap<String, String>,
@@ -144,6 +294,79 @@ struct AiVChat {
     tr_mode: bool,
     new_chat: bool,
     n_new_chat: String,
+
+    sessions: session::SessionStore,
+    session_list: Vec<session::SessionInfo>,
+    session: session::Session,
+    rename_id: Option<String>,
+    rename_text: String,
+    // Tags of the renamed session as typed, separated by commas
+    rename_tags: String,
+
+    usage_report: usage::UsageReport,
+    last_stats: Option<usage::ExchangeStats>,
+    show_usage: bool,
+    // Shown while a failed request waits to be sent again
+    retry_note: Option<String>,
+    // Tokens of the last request, the context limit and the number of messages left out
+    context_usage: Option<(u64, Option<u64>, usize)>,
+    s_policies: combo_box::State<config::ContextPolicy>,
+
+    // Images sent with the next prompt
+    images: Vec<attachments::ImageAttachment>,
+    files: Vec<attachments::FileAttachment>,
+
+    // Tool call waiting for the user to allow it
+    pending_tool: Option<tools::ToolCall>,
+    // Tools allowed without asking until the app is closed
+    allowed_tools: HashSet<String>,
+
+    // The prompt goes to every chat picked for comparison instead of the selected one
+    compare: bool,
+    compare_sel: Vec<String>,
+    columns: Vec<compare::Column>,
+    // Prompt of the comparison, it joins the conversation with the winning answer
+    compare_prompt: Option<chat::ChatMessage>,
+    compare_tasks: Vec<iced::task::Handle>,
+
+    // Message of the conversation being edited, the query is sent as an alternative to it
+    edit_at: Option<usize>,
+    // Answer to be replaced by another one, set until the query is sent
+    regenerate: Option<usize>,
+
+    // Settings of the documents as edited, the worker gets them when saved
+    rag: config::RagConfig,
+    rag_index: Option<Arc<rag::Index>>,
+    rag_progress: String,
+    rag_busy: bool,
+    // Passages given with the prompt, listed below the answer
+    sources: Vec<rag::Source>,
+
+    // Recording the query was dictated with, sent along with it
+    transcription: Option<transcribe::Transcription>,
+    recordings_dir: String,
+    sample_rate: u32,
+
+    search_index: search::SearchIndex,
+    show_search: bool,
+    search_filter: search::Filter,
+    // Dates of the filter as typed
+    search_from: String,
+    search_to: String,
+    search_hits: Vec<search::Hit>,
+    s_search_providers: combo_box::State<String>,
+    s_roles: combo_box::State<search::RoleFilter>,
+    // Message opened from the search results, marked in the list of messages
+    found_at: Option<usize>,
+
+    titles: config::TitlesConfig,
+    // Sessions waiting for their title
+    title_pending: HashSet<String>,
+
+    translation: config::TranslationConfig,
+    tr_target: Language,
+    tr_targets: combo_box::State<Language>,
+    translating: bool,
 }
 
 pub fn run(theme: &str) -> Result<(), iced::Error> {
@@ -184,10 +407,33 @@ impl AiVChat {
                 vec![]
             });
 
+        {
+            // Older configs told the voices apart by the key of their entry, the flag is saved once set
+            let mut c = config.blocking_write();
+            if let Some(a) = c.ai_chats.get_mut("elevenlabs").filter(|a| !a.speech) {
+                info!("Marking the elevenlabs entry as a voice");
+                a.speech = true;
+                match toml::to_string(&*c) {
+                    Ok(s) => if let Err(e) = std::fs::write(CONFIG, s) {
+                        error!("Error saving config data: {}", e.to_string());
+                    },
+                    Err(e) => error!("Error saving config data: {}", e.to_string()),
+                }
+            }
+        }
         let c = config.blocking_read().clone();
         let lang = c.tr_lang;
         //let ai_chat = c.sel_chat;
-        let prompt_context = c.prompt_context;
+        let mut prompts = c.prompts.clone();
+        let mut default_prompt = c.sel_prompt.clone();
+        if let Some(ctx) = c.prompt_context.clone().filter(|p| !p.is_empty()) {
+            if prompts.is_empty() {
+                info!("Moving the prompt context into the prompt library");
+                prompts.push(config::Prompt { name: "Default".to_string(), text: ctx });
+                default_prompt = Some("Default".to_string());
+            }
+        }
+        let s_prompts = combo_box::State::new(prompts.iter().map(|p| p.name.clone()).collect());
         
         let s_ai_table = c.ai_chats.clone();
         let s_ai_chats = combo_box::State::new(
@@ -201,6 +447,47 @@ impl AiVChat {
             None
         };
         let new_chat = s_ai_chat.is_none();
+        let gen_inputs = gen_inputs(s_ai_chat.as_ref());
+        let price_inputs = price_inputs(s_ai_chat.as_ref());
+        let usage_report = usage::UsageReport::load(USAGE_FILE)
+            .unwrap_or_else(|e| {
+                error!("Cannot read usage report: {}", e.to_string());
+                usage::UsageReport::default()
+            });
+
+        let rag = c.rag.clone().unwrap_or_default();
+        let rag_index = rag::Index::load(rag::INDEX_FILE)
+            .unwrap_or_else(|e| {
+                error!("Cannot read the documents index: {}", e.to_string());
+                None
+            })
+            .map(Arc::new);
+        let rag_progress = rag_index.as_ref()
+            .map(|i| format!("{} files, {} passages", i.files(), i.chunks()))
+            .unwrap_or_default();
+
+        let sessions = session::SessionStore::new(c.sessions_dir.clone().unwrap_or(SESSIONS_DIR.to_string()));
+        let session_list = sessions.list()
+            .unwrap_or_else(|e| {
+                error!("Cannot list sessions: {}", e.to_string());
+                vec![]
+            });
+        let session = session::Session::new(s_ai_chat.as_ref().map(|e| e.name.clone()).unwrap_or_default());
+        let mut search_index = search::SearchIndex::load(search::INDEX_FILE)
+            .unwrap_or_else(|e| {
+                error!("Cannot read the search index: {}", e.to_string());
+                None
+            })
+            .unwrap_or_default();
+        match search_index.sync(&sessions) {
+            Ok(true) => {
+                if let Err(e) = search_index.save(search::INDEX_FILE) {
+                    error!("Cannot save the search index: {}", e.to_string());
+                }
+            }
+            Ok(false) => {}
+            Err(e) => error!("Cannot index sessions: {}", e.to_string()),
+        }
 
         let theme = Theme::ALL.iter().find(|t|
             t.to_string() == c.theme)
@@ -232,6 +519,9 @@ impl AiVChat {
             query_text: text_editor::Content::new(),
             result_text: markdown::Content::new(),
             result_raw: vec![],
+            reasoning_text: markdown::Content::new(),
+            reasoning_raw: String::new(),
+            show_reasoning: false,
             theme,
             themes: combo_box::State::new(s_themes),
             play: false,
@@ -255,7 +545,11 @@ impl AiVChat {
             show_modal: false,
             modal_text: String::new(),
 
-            prompt_context: prompt_context.unwrap_or_default(),
+            prompts,
+            s_prompts,
+            prompt_edit: None,
+            prompt_editor: text_editor::Content::new(),
+            default_prompt,
 
             /*ai_chat,
             ai_chats: s_ai_chats,
@@ -265,8 +559,15 @@ impl AiVChat {
             s_ai_chat,
             s_ai_table,
             s_ai_chats,
+            s_kinds: combo_box::State::new(config::BackendKind::ALL.to_vec()),
+            s_models: combo_box::State::new(vec![]),
+            gen_inputs,
+            price_inputs,
+            ollama_pull: String::new(),
+            ollama_progress: String::new(),
             ai_editor_dirty: false,
             ai_cmd: None,
+            streaming: false,
 
             v_sender: None,
             voices,
@@ -274,6 +575,62 @@ impl AiVChat {
             tr_mode: false,
             new_chat,
             n_new_chat: String::new(),
+
+            sessions,
+            session_list,
+            session,
+            rename_id: None,
+            rename_text: String::new(),
+            rename_tags: String::new(),
+
+            usage_report,
+            last_stats: None,
+            show_usage: false,
+            retry_note: None,
+            context_usage: None,
+            s_policies: combo_box::State::new(config::ContextPolicy::ALL.to_vec()),
+
+            images: vec![],
+            files: vec![],
+            pending_tool: None,
+            allowed_tools: HashSet::new(),
+
+            compare: false,
+            compare_sel: vec![],
+            columns: vec![],
+            compare_prompt: None,
+            compare_tasks: vec![],
+
+            edit_at: None,
+            regenerate: None,
+
+            rag,
+            rag_index,
+            rag_progress,
+            rag_busy: false,
+            sources: vec![],
+
+            transcription: None,
+            recordings_dir: c.recordings_dir.clone().unwrap_or_default(),
+            sample_rate: 16000,
+
+            search_index,
+            show_search: false,
+            search_filter: search::Filter::default(),
+            search_from: String::new(),
+            search_to: String::new(),
+            search_hits: vec![],
+            s_search_providers: combo_box::State::new(vec![]),
+            s_roles: combo_box::State::new(search::RoleFilter::ALL.to_vec()),
+            found_at: None,
+
+            titles: c.titles.clone().unwrap_or_default(),
+            title_pending: HashSet::new(),
+
+            tr_target: c.translation.as_ref().map(|t| Language::from(t.target.clone())).unwrap_or_default(),
+            translation: c.translation.clone().unwrap_or_default(),
+            tr_targets: combo_box::State::new(Language::ALL.to_vec()),
+            translating: false,
         }
     }
 
@@ -283,6 +640,44 @@ impl AiVChat {
         let w = config.width;
         let h = config.height;
 
+        // Errors are shown over any panel
+        if self.show_modal {
+            let alert = container(
+                column![ 
+                    text(self.modal_text.as_str()),
+                    button(text("OK")).on_press(Message::HideModal) 
+                ].align_x(iced::Alignment::Center)
+                .spacing(10)
+                ).width(w).height(h).padding(10).align_x(iced::Alignment::Center).align_y(iced::Alignment::Center);
+            return alert.into();
+        }
+
+        if let Some(call) = self.pending_tool.as_ref() {
+            let confirm = container(
+                column![
+                    text(format!("The model wants to call {}", call.name)),
+                    container(text(call.arguments.as_str()).font(iced::Font::MONOSPACE))
+                        .padding(10)
+                        .style(container::rounded_box),
+                    row![
+                        button("Allow").on_press(Message::ToolDecision(true)),
+                        button(text(format!("Always allow {}", call.name))).on_press(Message::ToolAlwaysAllow),
+                        button("Deny").on_press(Message::ToolDecision(false)).style(button::secondary),
+                    ].spacing(10)
+                ].align_x(iced::Alignment::Center)
+                .spacing(10)
+                ).width(w).height(h).padding(10).align_x(iced::Alignment::Center).align_y(iced::Alignment::Center);
+            return confirm.into();
+        }
+
+        if self.show_usage {
+            return self.view_usage();
+        }
+
+        if self.show_search {
+            return self.view_search();
+        }
+
         // Settings panel
         if self.settings {
             let label_w = 180.0;
@@ -308,9 +703,29 @@ impl AiVChat {
             let ids_lang = text("Transcription language").width(label_w);
             let idc_lang:ComboBox<'_, Language, Message> = combo_box(&self.tr_languages, "", self.tr_language.as_ref(), Message::LanguageSelected);
 
-            let ids_ctx = text("Prompt context").width(label_w);
-            let idc_ctx: TextInput<Message> = text_input("", &self.prompt_context)
-                .on_input(Message::CtxInput);
+            let ids_prompts = text("System prompts").width(label_w);
+            let edited = self.prompt_edit.and_then(|i| self.prompts.get(i));
+            let idc_prompts: ComboBox<'_, String, Message> = combo_box(&self.s_prompts, "select prompt", edited.map(|p| &p.name), Message::PromptPicked);
+            let idc_new_prompt: Button<Message> = button("New").on_press(Message::NewPrompt);
+            let idc_del_prompt: Button<Message> = button("Delete").on_press_maybe(edited.map(|_| Message::DeletePrompt));
+            let idc_prompt: Element<'_, Message> = if let Some(p) = edited {
+                let idc_name: TextInput<Message> = text_input("Prompt name", &p.name)
+                    .on_input(Message::PromptNameChanged);
+                let idc_text = text_editor(&self.prompt_editor)
+                    .on_action(Message::PromptEditAction)
+                    .height(120.0);
+                column![
+                    row![text("Prompt name").width(label_w), idc_name].spacing(15.0).padding(5.0),
+                    row![
+                        text("Text, may use {{date}} {{time}} {{language}} {{clipboard}} {{selection}}").width(label_w),
+                        idc_text
+                    ].spacing(15.0).padding(5.0),
+                ].into()
+            } else {
+                column![].into()
+            };
+            let ids_def_prompt = text("Default prompt").width(label_w);
+            let idc_def_prompt: ComboBox<'_, String, Message> = combo_box(&self.s_prompts, "none", self.default_prompt.as_ref(), Message::DefaultPromptSelected);
 
             let ids_chat = text("AI Chat").width(label_w);
             //let idc_chat:ComboBox<'_, String, Message> = combo_box(&self.ai_chats, "", self.ai_chat.as_ref(), Message::ChatSelected);
@@ -337,36 +752,168 @@ impl AiVChat {
             let ids_chat_model = text("Api Model").width(label_w);
             let idc_chat_model: TextInput<Message> = text_input("Api Model", &model )
                 .on_input(Message::ChatApiModelChanged);
+            let idc_fetch: Button<Message> = button("Fetch models").on_press_maybe(self.s_ai_chat.as_ref().map(|_| Message::FetchModels));
+            let sel_model = self.s_ai_chat.as_ref().map(|s| &s.model);
+            let idc_models: Element<'_, Message> = if self.s_models.options().is_empty() {
+                column![].into()
+            } else {
+                let ids_models = text("Available models").width(label_w);
+                let idc_models: ComboBox<'_, String, Message> = combo_box(&self.s_models, "select model", sel_model, Message::ModelSelected);
+                row![ids_models, idc_models].spacing(15.0).padding(5.0).into()
+            };
+
+            let kind = self.s_ai_chat.as_ref().map(|s| s.kind);
+            let ids_chat_kind = text("Api Type").width(label_w);
+            let idc_chat_kind: ComboBox<'_, config::BackendKind, Message> = combo_box(&self.s_kinds, "", kind.as_ref(), Message::ChatApiKindChanged);
+            let tools_on = self.s_ai_chat.as_ref().is_some_and(|s| s.tools);
+            let idc_tools: checkbox::Checkbox<'_, Message> = checkbox("Allow tools", tools_on)
+                .on_toggle_maybe(self.s_ai_chat.as_ref().map(|_| Message::ToolsToggle));
+            let vision_on = self.s_ai_chat.as_ref().is_some_and(|s| s.vision);
+            let idc_vision: checkbox::Checkbox<'_, Message> = checkbox("Accepts images", vision_on)
+                .on_toggle_maybe(self.s_ai_chat.as_ref().map(|_| Message::VisionToggle));
+
+            let idc_ollama: Element<'_, Message> = if kind == Some(config::BackendKind::Ollama) {
+                let ollama = self.s_ai_chat.as_ref().and_then(|s| s.ollama.clone()).unwrap_or_default();
+                let m = if model.is_empty() { None } else { Some(Message::OllamaDelete) };
+                let idc_delete: Button<Message> = button("Delete model").on_press_maybe(m);
+
+                let ids_pull = text("Pull model").width(label_w);
+                let idc_pull_name: TextInput<Message> = text_input("e.g. gemma3:27b", &self.ollama_pull)
+                    .on_input(Message::OllamaPullInput)
+                    .on_submit(Message::OllamaPull);
+                let m = if self.ollama_pull.is_empty() { None } else { Some(Message::OllamaPull) };
+                let idc_pull: Button<Message> = button("Pull").on_press_maybe(m);
+
+                let keep_alive = ollama.keep_alive.unwrap_or_default();
+                let ids_keep_alive = text("Keep alive").width(label_w);
+                let idc_keep_alive: TextInput<Message> = text_input("e.g. 10m", &keep_alive)
+                    .on_input(Message::OllamaKeepAliveChanged);
+                let num_ctx = ollama.num_ctx.map(|n| n.to_string()).unwrap_or_default();
+                let idc_num_ctx: TextInput<Message> = text_input("num_ctx", &num_ctx)
+                    .on_input(Message::OllamaNumCtxChanged)
+                    .width(100.0);
+
+                column![
+                    row![ids_pull, idc_pull_name, idc_pull, idc_delete, text(self.ollama_progress.as_str())].spacing(15.0).padding(5.0),
+                    row![ids_keep_alive, idc_keep_alive, text("Context size"), idc_num_ctx].spacing(15.0).padding(5.0),
+                ].into()
+            } else {
+                column![].into()
+            };
+
+            let mut idc_params = row![text("Generation").width(label_w)].spacing(10.0).padding(5.0);
+            for (p, v) in config::GenParam::ALL.iter().zip(self.gen_inputs.iter()) {
+                let p = *p;
+                let input: TextInput<Message> = text_input(p.label(), v)
+                    .on_input(move |s| Message::GenParamChanged(p, s))
+                    .width(90.0);
+                idc_params = idc_params.push(input);
+            }
+
+            let ids_price = text("Price per 1M tokens").width(label_w);
+            let idc_price_in: TextInput<Message> = text_input("input", &self.price_inputs[0])
+                .on_input(Message::PriceInChanged)
+                .width(90.0);
+            let idc_price_out: TextInput<Message> = text_input("output", &self.price_inputs[1])
+                .on_input(Message::PriceOutChanged)
+                .width(90.0);
+
+            let timeout = self.s_ai_chat.as_ref().and_then(|s| s.timeout).map(|t| t.to_string()).unwrap_or_default();
+            let ids_timeout = text("Request timeout (s)").width(label_w);
+            let idc_timeout: TextInput<Message> = text_input("120", &timeout)
+                .on_input(Message::TimeoutChanged)
+                .width(90.0);
+
+            let context_length = self.s_ai_chat.as_ref().and_then(|s| s.context_length).map(|t| t.to_string()).unwrap_or_default();
+            let policy = self.s_ai_chat.as_ref().map(|s| s.context_policy);
+            let ids_context = text("Context length").width(label_w);
+            let idc_context: TextInput<Message> = text_input("tokens", &context_length)
+                .on_input(Message::ContextLengthChanged)
+                .width(90.0);
+            let idc_policy: ComboBox<'_, config::ContextPolicy, Message> = combo_box(&self.s_policies, "when full", policy.as_ref(), Message::ContextPolicyChanged)
+                .width(160.0);
+
+            let ids_rag = text("Documents").width(label_w);
+            let idc_rag_on: checkbox::Checkbox<'_, Message> = checkbox("Use documents", self.rag.enabled)
+                .on_toggle(Message::RagToggle);
+            let idc_rag_folder: TextInput<Message> = text_input("Folder of notes or docs", &self.rag.folder)
+                .on_input(Message::RagFolderChanged);
+            let idc_rag_browse: Button<Message> = button("Browse").on_press(Message::RagBrowse);
+            let ids_rag_api = text("Embeddings Url and model").width(label_w);
+            let idc_rag_url: TextInput<Message> = text_input("e.g. http://localhost:11434/v1", &self.rag.url)
+                .on_input(Message::RagUrlChanged);
+            let idc_rag_model: TextInput<Message> = text_input("e.g. nomic-embed-text", &self.rag.model)
+                .on_input(Message::RagModelChanged)
+                .width(200.0);
+            let idc_rag_key: TextInput<Message> = text_input("Api key", &self.rag.key)
+                .on_input(Message::RagKeyChanged)
+                .width(200.0);
+            let top_k = self.rag.top_k.map(|k| k.to_string()).unwrap_or_default();
+            let ids_rag_index = text("Passages per prompt").width(label_w);
+            let idc_rag_top_k: TextInput<Message> = text_input("4", &top_k)
+                .on_input(Message::RagTopKChanged)
+                .width(90.0);
+            let idc_rag_index: Button<Message> = button("Index").on_press_maybe((!self.rag_busy).then_some(Message::RagIndex));
 
             let ids_tr_model = text("Transciber model").width(label_w);
             let idc_tr_model: TextInput<Message> = text_input("Transciption model", &self.vmodel)
                 .on_input(Message::TrModelChanged);
+            let ids_recordings = text("Keep recordings in").width(label_w);
+            let idc_recordings: TextInput<Message> = text_input("folder, recordings are not kept when empty", &self.recordings_dir)
+                .on_input(Message::RecordingsDirChanged);
+
+            let ids_titles = text("Titles and tags").width(label_w);
+            let idc_titles_on: checkbox::Checkbox<'_, Message> = checkbox("Name sessions after the first answer", self.titles.enabled)
+                .on_toggle(Message::TitlesToggle);
+            let title_chat = self.titles.chat.as_ref()
+                .and_then(|n| self.s_ai_table.values().find(|a| a.name == *n));
+            let idc_title_chat: ComboBox<'_, config::AiApi, Message> = combo_box(&self.s_ai_chats, "chat of the session", title_chat, Message::TitleChatSelected)
+                .width(200.0);
+            let idc_title_session: Button<Message> = button("Chat of the session")
+                .on_press_maybe(self.titles.chat.as_ref().map(|_| Message::TitleChatOfSession));
+
+            let ids_translation = text("Translate with").width(label_w);
+            let translation_chat = self.translation.chat.as_ref()
+                .and_then(|n| self.s_ai_table.values().find(|a| a.name == *n));
+            let idc_translation_chat: ComboBox<'_, config::AiApi, Message> = combo_box(&self.s_ai_chats, "chat of the session", translation_chat, Message::TranslateChatSelected)
+                .width(200.0);
+            let idc_translation_session: Button<Message> = button("Chat of the session")
+                .on_press_maybe(self.translation.chat.as_ref().map(|_| Message::TranslateChatOfSession));
+            let idc_whisper_english: checkbox::Checkbox<'_, Message> = checkbox("Whisper translates to English, offline", self.translation.whisper_english)
+                .on_toggle(Message::WhisperEnglishToggle);
 
             let idc_close: Button<Message> = button("Cancel").on_press(Message::ToggleSettings);
             let idc_save: Button<Message> = button("Save").on_press(Message::SaveSettings);
 
-            return column![
+            // The prompt editor makes the panel taller than the window
+            return scrollable(column![
                 row![ids_dev, idc_dev].spacing(15.0).padding(5.0),
                 row![ids_font, idc_font, idc_font_up, idc_font_down].spacing(15.0).padding(5.0),
                 row![ids_theme, idc_theme].spacing(15.0).padding(5.0),
                 row![ids_lang, idc_lang].spacing(15.0).padding(5.0),
-                row![ids_ctx, idc_ctx].spacing(15.0).padding(5.0),
+                row![ids_prompts, idc_prompts, idc_new_prompt, idc_del_prompt].spacing(15.0).padding(5.0),
+                idc_prompt,
+                row![ids_def_prompt, idc_def_prompt].spacing(15.0).padding(5.0),
                 row![ids_chat, idc_chat, idc_new].spacing(15.0).padding(5.0),
+                row![ids_chat_kind, idc_chat_kind, idc_tools, idc_vision].spacing(15.0).padding(5.0),
                 row![ids_chat_key, idc_chat_key].spacing(15.0).padding(5.0),
                 row![ids_chat_url, idc_chat_url].spacing(15.0).padding(5.0),
-                row![ids_chat_model, idc_chat_model].spacing(15.0).padding(5.0),
+                row![ids_chat_model, idc_chat_model, idc_fetch].spacing(15.0).padding(5.0),
+                idc_models,
+                idc_params,
+                row![ids_price, idc_price_in, idc_price_out].spacing(15.0).padding(5.0),
+                row![ids_timeout, idc_timeout].spacing(15.0).padding(5.0),
+                row![ids_context, idc_context, idc_policy].spacing(15.0).padding(5.0),
+                idc_ollama,
+                row![ids_rag, idc_rag_on, idc_rag_folder, idc_rag_browse].spacing(15.0).padding(5.0),
+                row![ids_rag_api, idc_rag_url, idc_rag_model, idc_rag_key].spacing(15.0).padding(5.0),
+                row![ids_rag_index, idc_rag_top_k, idc_rag_index, text(self.rag_progress.as_str())].spacing(15.0).padding(5.0),
                 row![ids_tr_model, idc_tr_model].spacing(15.0).padding(5.0),
+                row![ids_recordings, idc_recordings].spacing(15.0).padding(5.0),
+                row![ids_translation, idc_translation_chat, idc_translation_session, idc_whisper_english].spacing(15.0).padding(5.0),
+                row![ids_titles, idc_titles_on, idc_title_chat, idc_title_session].spacing(15.0).padding(5.0),
                 row![idc_save, idc_close].spacing(15.0).padding(5.0),
-            ].padding(25.0).into();
-        } else if self.show_modal {
-            let alert = container(
-                column![ 
-                    text(self.modal_text.as_str()),
-                    button(text("OK")).on_press(Message::HideModal) 
-                ].align_x(iced::Alignment::Center)
-                .spacing(10)
-                ).width(w).height(h).padding(10).align_x(iced::Alignment::Center).align_y(iced::Alignment::Center);
-            return alert.into();
+            ].padding(25.0)).into();
         }
 
         let t_h = if self.tr_mode { 0.90 } else { 0.35 };
@@ -389,13 +936,20 @@ impl AiVChat {
             .on_toggle(Message::PlayToggle);
 
         let idc_settings: Button<Message> = button("Settings").on_press(Message::ToggleSettings);
-        let ask_m = if text_empty || self.s_ai_chat.is_none() {
+        let ready = if self.compare { self.compare_sel.len() >= 2 } else { self.s_ai_chat.is_some() };
+        let ask_m = if text_empty || !ready {
             None
         } else {
             Some(Message::AskChat)
         };
         let idc_ask: Button<Message> = button("Ask").on_press_maybe(ask_m);
+        let sel_prompt = self.session.prompt.as_ref().or(self.default_prompt.as_ref());
+        let idc_prompt: ComboBox<'_, String, Message> = combo_box(&self.s_prompts, "System prompt", sel_prompt, Message::SessionPromptSelected)
+            .width(160.0);
+        let idc_stop: Button<Message> = button("Stop").on_press_maybe(self.streaming.then_some(Message::StopChat));
+        let idc_new_conv: Button<Message> = button("New conversation").on_press(Message::NewConversation);
         let idc_copy: Button<Message> = button("Copy result").on_press(Message::CopyResult);
+        let idc_export: Button<Message> = button("Export").on_press_maybe((!self.session.is_empty()).then_some(Message::Export));
         let m_cc = if self.result_raw.is_empty() {
             None
         } else {
@@ -403,6 +957,14 @@ impl AiVChat {
         };
         let idc_cc: Button<Message> = button("Code").on_press_maybe(m_cc);
         let idc_tr = checkbox("Transcriber only", self.tr_mode).on_toggle(Message::TrModeToggle);
+        let idc_compare = checkbox("Compare", self.compare).on_toggle(Message::CompareToggle);
+        let idc_translate = checkbox("Translate to", self.translation.enabled).on_toggle(Message::TranslateToggle);
+        let idc_target: ComboBox<'_, Language, Message> = combo_box(&self.tr_targets, "", Some(&self.tr_target), Message::TranslateTargetSelected)
+            .width(70.0);
+        let idc_usage: Button<Message> = button("Usage").on_press(Message::ToggleUsage);
+        let idc_attach: Button<Message> = button("Image").on_press(Message::AttachImage);
+        let idc_attach_file: Button<Message> = button("File").on_press(Message::AttachFile);
+        let idc_paste: Button<Message> = button("Paste image").on_press(Message::PasteImage);
 
         let button_row = row![
             b_up.padding(5.0),
@@ -413,26 +975,363 @@ impl AiVChat {
             text(" "),
             idc_settings.padding(5.0),
             idc_ask.padding(5.0),
+            idc_prompt,
+            idc_stop.padding(5.0),
+            idc_new_conv.padding(5.0),
             idc_copy.padding(5.0),
+            idc_export.padding(5.0),
             idc_cc.padding(5.0),
+            idc_usage.padding(5.0),
+            idc_attach.padding(5.0),
+            idc_attach_file.padding(5.0),
+            idc_paste.padding(5.0),
             text(" "),
             idc_tr,
+            idc_compare,
+            idc_translate,
+            idc_target,
         ].padding(5.0).spacing(5.0);
+
+        let idc_compare_sel: Element<'_, Message> = if self.compare {
+            let mut names: Vec<&String> = self.s_ai_table.values()
+                .filter(|a| a.is_chat())
+                .map(|a| &a.name)
+                .collect();
+            names.sort();
+            let mut sel = row![text("Compare")].spacing(10.0).padding(5.0);
+            for n in names {
+                let name = n.clone();
+                sel = sel.push(checkbox(n.as_str(), self.compare_sel.contains(n))
+                    .on_toggle(move |on| Message::CompareChatToggle(name.clone(), on)));
+            }
+            sel.into()
+        } else {
+            column![].into()
+        };
+
+        let mut status = format!("Session: {}", self.session.usage.summary());
+        if let Some(stats) = self.last_stats.as_ref() {
+            status.push_str(format!(" | Last: {}", stats.summary()).as_str());
+        }
+        if let Some(note) = self.retry_note.as_ref() {
+            status.push_str(format!(" | {}", note).as_str());
+        }
+        let idc_status = text(status).size(12);
+        let idc_context: Element<'_, Message> = match self.context_usage {
+            Some((used, Some(limit), left_out)) => {
+                let mut note = format!("Context: {} / {} tokens", used, limit);
+                if left_out > 0 {
+                    note.push_str(format!(", {} older messages left out", left_out).as_str());
+                }
+                row![
+                    progress_bar(0.0..=limit as f32, used as f32).length(200.0).girth(8.0),
+                    text(note).size(12),
+                ].spacing(10.0).align_y(iced::Alignment::Center).into()
+            }
+            Some((used, None, _)) => text(format!("Context: {} tokens", used)).size(12).into(),
+            None => column![].into(),
+        };
             
         let idc_result: Element<'_, Message> = if self.tr_mode {
             text("").into()
+        } else if !self.columns.is_empty() {
+            self.view_compare()
+        } else {
+            let answer: Element<'_, Message> = markdown::view(self.result_text.items(), self.theme())
+                .map(Message::LinkClicked).into();
+            if self.reasoning_raw.is_empty() {
+                answer
+            } else {
+                let label = if self.show_reasoning { "▼ Reasoning" } else { "▶ Reasoning" };
+                let idc_reasoning: Button<Message> = button(text(label))
+                    .style(button::text)
+                    .on_press(Message::ToggleReasoning);
+                let mut c = column![idc_reasoning].spacing(5.0);
+                if self.show_reasoning {
+                    let reasoning = markdown::view(self.reasoning_text.items(), self.theme())
+                        .map(Message::LinkClicked);
+                    c = c.push(container(reasoning).padding(10.0).style(container::rounded_box));
+                }
+                c.push(answer).into()
+            }
+        };
+
+        let mut thumbnails = row![].spacing(5.0).padding(5.0);
+        for (i, a) in self.images.iter().enumerate() {
+            let idc_remove: Button<Message> = button(text("✕").size(12))
+                .style(button::text)
+                .on_press(Message::RemoveImage(i));
+            thumbnails = thumbnails.push(column![
+                image(a.handle.clone()).height(64.0),
+                row![text(a.name.as_str()).size(12), idc_remove].align_y(iced::Alignment::Center),
+            ]);
+        }
+        for (i, f) in self.files.iter().enumerate() {
+            let idc_remove: Button<Message> = button(text("✕").size(12))
+                .style(button::text)
+                .on_press(Message::RemoveFile(i));
+            let warning = if f.truncated { ", truncated" } else { "" };
+            let label = format!("{} ({}, ~{} tokens{})", f.name, f.encoding, f.tokens, warning);
+            thumbnails = thumbnails.push(
+                container(row![text(label).size(12), idc_remove].align_y(iced::Alignment::Center))
+                    .padding(5.0)
+                    .style(container::rounded_box)
+            );
+        }
+        if !self.files.is_empty() {
+            let total: u64 = self.files.iter().map(|f| f.tokens).sum();
+            thumbnails = thumbnails.push(text(format!("Files: ~{} tokens", total)).size(12));
+        }
+
+        let idc_edit: Element<'_, Message> = if self.edit_at.is_some() {
+            row![
+                text("Editing a message, Ask sends it as a new branch").size(12),
+                button(text("Cancel").size(12)).on_press(Message::CancelEdit),
+            ].spacing(10.0).padding(5.0).align_y(iced::Alignment::Center).into()
         } else {
-            markdown::view(self.result_text.items(), self.theme())
-                .map(Message::LinkClicked).into()
+            column![].into()
+        };
+
+        let idc_translation: Element<'_, Message> = match self.transcription.as_ref() {
+            Some(t) if t.translation.is_some() || self.translating => {
+                let source = self.tr_language.unwrap_or(Language::EN);
+                let translated = t.translation.as_deref().unwrap_or("Translating...");
+                row![
+                    column![
+                        text(format!("Original, {}", source.name())).size(12),
+                        text(t.text.as_str()),
+                        button(text("Use").size(12)).on_press(Message::SetText(t.text.clone())),
+                    ].spacing(5.0).width(iced::Length::FillPortion(1)),
+                    column![
+                        text(format!("Translation, {}", self.tr_target.name())).size(12),
+                        text(translated),
+                        button(text("Use").size(12)).on_press_maybe(t.translation.clone().map(Message::SetText)),
+                    ].spacing(5.0).width(iced::Length::FillPortion(1)),
+                ].spacing(10.0).padding(5.0).into()
+            }
+            _ => column![].into(),
         };
 
         let controls = column![
+            idc_edit,
+            thumbnails,
+            idc_translation,
             idc_text,
             button_row,
-            scrollable(idc_result)
+            idc_compare_sel,
+            idc_status,
+            idc_context,
+            scrollable(column![self.view_messages(), idc_result].spacing(10.0))
         ];
 
-        controls.into()
+        row![self.view_sessions(), controls].into()
+    }
+
+    /// The messages of the branch shown, with their actions and the arrows to their alternatives
+    fn view_messages(&self) -> Element<'_, Message> {
+        let idle = !self.streaming;
+        let mut list = column![].spacing(2.0);
+        for (pos, m) in self.session.messages.iter().enumerate() {
+            let who = match m.role {
+                ChatCompletionMessageRole::User => "You",
+                ChatCompletionMessageRole::Assistant => "Assistant",
+                _ => continue,
+            };
+            let content = m.content.as_deref().unwrap_or_default();
+            if content.is_empty() && m.images.is_empty() && m.files.is_empty() {
+                continue;
+            }
+            let line = content.lines().next().unwrap_or_default();
+            let preview: String = line.chars().take(PREVIEW_LEN).collect();
+            let mut entry = row![
+                text(who).size(12).width(70.0),
+                text(preview).size(12).width(iced::Length::Fill),
+            ].spacing(5.0).align_y(iced::Alignment::Center);
+            let (i, n) = self.session.siblings(pos);
+            if n > 1 {
+                entry = entry.push(button(text("‹").size(12))
+                    .style(button::text)
+                    .on_press_maybe((idle && i > 0).then_some(Message::SwitchBranch(pos, false))));
+                entry = entry.push(text(format!("{}/{}", i + 1, n)).size(12));
+                entry = entry.push(button(text("›").size(12))
+                    .style(button::text)
+                    .on_press_maybe((idle && i + 1 < n).then_some(Message::SwitchBranch(pos, true))));
+            }
+            let action = if m.role == ChatCompletionMessageRole::User {
+                button(text("✎").size(12)).on_press_maybe(idle.then_some(Message::EditMessage(pos)))
+            } else {
+                button(text("↻").size(12)).on_press_maybe(idle.then_some(Message::Regenerate(pos)))
+            };
+            entry = entry.push(action);
+            entry = entry.push(button(text("✕").size(12)).on_press_maybe(idle.then_some(Message::DeleteMessage(pos))));
+            if self.found_at == Some(pos) {
+                list = list.push(container(entry).style(container::rounded_box));
+            } else {
+                list = list.push(entry);
+            }
+        }
+        list.into()
+    }
+
+    fn view_compare(&self) -> Element<'_, Message> {
+        let mut columns = row![].spacing(10.0);
+        for (i, c) in self.columns.iter().enumerate() {
+            let stats = match c.stats.as_ref() {
+                Some(s) => format!("{}, {:.1}s total, ${:.4}", s.summary(), s.duration_ms as f64 / 1000.0, s.cost),
+                None if !c.done => "Answering...".to_string(),
+                None => String::new(),
+            };
+            let idc_pick: Button<Message> = button("Pick winner")
+                .on_press_maybe(c.can_win().then_some(Message::PickWinner(i)));
+            let answer = markdown::view(c.text.items(), self.theme())
+                .map(Message::LinkClicked);
+            columns = columns.push(column![
+                text(c.api.name.as_str()),
+                text(c.api.model.as_str()).size(12),
+                text(stats).size(12),
+                idc_pick,
+                answer,
+            ].spacing(5.0).width(iced::Length::FillPortion(1)));
+        }
+        columns.into()
+    }
+
+    fn view_usage(&self) -> Element<'_, Message> {
+        let col_w = 120.0;
+        let mut table = column![
+            row![
+                text("Provider").width(200.0),
+                text("Requests").width(col_w),
+                text("Tokens in").width(col_w),
+                text("Tokens out").width(col_w),
+                text("Cost").width(col_w),
+                text("First token").width(col_w),
+                text("Tokens/s").width(col_w),
+            ].spacing(5.0)
+        ].spacing(5.0);
+        for (name, t) in self.usage_report.providers.iter() {
+            let ttft = t.avg_ttft_ms().map(|ms| format!("{:.2}s", ms as f64 / 1000.0)).unwrap_or_default();
+            let tps = t.avg_tokens_per_sec().map(|v| format!("{:.1}", v)).unwrap_or_default();
+            table = table.push(row![
+                text(name.as_str()).width(200.0),
+                text(t.requests.to_string()).width(col_w),
+                text(t.prompt_tokens.to_string()).width(col_w),
+                text(t.completion_tokens.to_string()).width(col_w),
+                text(format!("${:.4}", t.cost)).width(col_w),
+                text(ttft).width(col_w),
+                text(tps).width(col_w),
+            ].spacing(5.0));
+        }
+        let total = self.usage_report.total();
+        let idc_close: Button<Message> = button("Close").on_press(Message::ToggleUsage);
+        column![
+            text("Usage"),
+            scrollable(table),
+            text(format!("Total: {}", total.summary())),
+            idc_close,
+        ].spacing(15.0).padding(25.0).into()
+    }
+
+    fn view_sessions(&self) -> Element<'_, Message> {
+        let idc_import: Button<Message> = button(text("Import").size(12)).on_press(Message::Import);
+        let idc_search: Button<Message> = button(text("Search").size(12)).on_press(Message::ToggleSearch);
+        let mut list = column![
+            row![text("Conversations").width(iced::Length::Fill), idc_search, idc_import].spacing(2.0).align_y(iced::Alignment::Center)
+        ].spacing(5.0).padding(5.0);
+        for s in self.session_list.iter() {
+            let entry: Element<'_, Message> = if self.rename_id.as_ref() == Some(&s.id) {
+                column![
+                    text_input("Title", &self.rename_text)
+                        .on_input(Message::RenameInput)
+                        .on_submit(Message::RenameSubmit),
+                    text_input("Tags, separated by commas", &self.rename_tags)
+                        .on_input(Message::RenameTagsInput)
+                        .on_submit(Message::RenameSubmit)
+                        .size(12),
+                ].spacing(2.0).into()
+            } else {
+                let title = if s.title.is_empty() { "Untitled" } else { s.title.as_str() };
+                let style = if s.id == self.session.id { button::primary } else { button::secondary };
+                let mut label = column![text(title), text(s.provider.as_str()).size(12)];
+                if !s.tags.is_empty() {
+                    let tags: Vec<String> = s.tags.iter().map(|t| format!("#{}", t)).collect();
+                    label = label.push(text(tags.join(" ")).size(12));
+                }
+                let idc_open: Button<Message> = button(label)
+                    .width(iced::Length::Fill)
+                    .style(style)
+                    .on_press(Message::OpenSession(s.id.clone()));
+                let idc_rename: Button<Message> = button("✎").on_press(Message::StartRename(s.id.clone()));
+                let idc_delete: Button<Message> = button("✕").on_press(Message::DeleteSession(s.id.clone()));
+                row![idc_open, idc_rename, idc_delete].spacing(2.0).into()
+            };
+            list = list.push(entry);
+        }
+        scrollable(list).width(240.0).into()
+    }
+
+    fn view_search(&self) -> Element<'_, Message> {
+        let idc_query: TextInput<Message> = text_input("Words to find", &self.search_filter.query)
+            .on_input(Message::SearchInput);
+        let idc_provider: ComboBox<'_, String, Message> = combo_box(&self.s_search_providers, "any chat", self.search_filter.provider.as_ref(), Message::SearchProvider)
+            .width(200.0);
+        let idc_any: Button<Message> = button("Any")
+            .on_press_maybe(self.search_filter.provider.as_ref().map(|_| Message::SearchAnyProvider));
+        let idc_role: ComboBox<'_, search::RoleFilter, Message> = combo_box(&self.s_roles, "", Some(&self.search_filter.role), Message::SearchRole)
+            .width(120.0);
+        let idc_from: TextInput<Message> = text_input("YYYY-MM-DD", &self.search_from)
+            .on_input(Message::SearchFromChanged)
+            .width(110.0);
+        let idc_to: TextInput<Message> = text_input("YYYY-MM-DD", &self.search_to)
+            .on_input(Message::SearchToChanged)
+            .width(110.0);
+        let filters = row![
+            text("Chat"), idc_provider, idc_any,
+            text("Role"), idc_role,
+            text("From"), idc_from,
+            text("To"), idc_to,
+        ].spacing(10.0).align_y(iced::Alignment::Center);
+
+        let accent = self.theme().palette().primary;
+        let bold = iced::Font { weight: iced::font::Weight::Bold, ..iced::Font::DEFAULT };
+        let mut hits = column![].spacing(5.0);
+        for h in self.search_hits.iter() {
+            let title = if h.title.is_empty() { "Untitled" } else { h.title.as_str() };
+            let who = if h.user { "You" } else { "Assistant" };
+            let spans: Vec<iced::widget::text::Span<'_, (), iced::Font>> = h.snippet.iter()
+                .map(|(s, hit)| {
+                    let span = iced::widget::span(s.as_str());
+                    if *hit { span.color(accent).font(bold) } else { span }
+                })
+                .collect();
+            let idc_hit: Button<Message> = button(column![
+                row![
+                    text(title),
+                    text(h.provider.as_str()).size(12),
+                    text(h.time.format("%Y-%m-%d %H:%M").to_string()).size(12),
+                    text(who).size(12),
+                ].spacing(10.0).align_y(iced::Alignment::Center),
+                iced::widget::rich_text(spans).size(12),
+            ].spacing(3.0))
+                .width(iced::Length::Fill)
+                .style(button::secondary)
+                .on_press(Message::OpenSearchHit(h.id.clone(), h.pos));
+            hits = hits.push(idc_hit);
+        }
+        let count = if self.search_filter.query.trim().is_empty() {
+            String::new()
+        } else {
+            format!("{} messages found", self.search_hits.len())
+        };
+        let idc_close: Button<Message> = button("Close").on_press(Message::ToggleSearch);
+        column![
+            text("Search conversations"),
+            idc_query,
+            filters,
+            text(count).size(12),
+            scrollable(hits).height(iced::Length::Fill),
+            idc_close,
+        ].spacing(15.0).padding(25.0).into()
     }
 
     pub fn theme(&self) -> Theme {
@@ -448,7 +1347,21 @@ impl AiVChat {
         let voice_stream = Subscription::run(voice::connect)
             .map(Message::VoiceEventRec);
 
-        let b = Subscription::batch(vec![chat_stream, record, voice_stream]);
+        let keys = iced::keyboard::on_key_press(|key, _modifiers| {
+            match key {
+                iced::keyboard::Key::Named(iced::keyboard::key::Named::Escape) => Some(Message::StopChat),
+                _ => None,
+            }
+        });
+
+        let dropped = iced::event::listen_with(|e, _status, _window| {
+            match e {
+                iced::Event::Window(iced::window::Event::FileDropped(path)) => Some(Message::FileDropped(path)),
+                _ => None,
+            }
+        });
+
+        let b = Subscription::batch(vec![chat_stream, record, voice_stream, keys, dropped]);
         b
     }
 
@@ -457,6 +1370,325 @@ impl AiVChat {
         self.show_modal = true;
     }
 
+    /// Sends the commands to the chat worker in order
+    fn send_chat(&self, cmds: Vec<chat::ChatCommand>) -> iced::Task<Message> {
+        if let Some(mut cmd) = self.ai_cmd.clone() {
+            iced::Task::perform(async move {
+                for c in cmds {
+                    cmd.send(c).await?;
+                }
+                Ok(())
+            }, |e: Result<(), mpsc::SendError>| {
+                if let Err(e) = e {
+                    Message::ShowError(e.to_string())
+                } else {
+                    Message::Void
+                }
+            })
+        } else {
+            iced::Task::none()
+        }
+    }
+
+    fn fetch_models(&self) -> iced::Task<Message> {
+        let Some(api) = self.s_ai_chat.clone() else {
+            return iced::Task::none();
+        };
+        iced::Task::perform(async move {
+            backend::from_api(&api).models().await
+                .map_err(|e| e.to_string())
+        }, Message::ModelsFetched)
+    }
+
+    fn reload_sessions(&mut self) {
+        match self.sessions.list() {
+            Ok(l) => self.session_list = l,
+            Err(e) => self.display_av(e.to_string()),
+        }
+    }
+
+    /// Puts the saved session in its place in the list, without reading every stored session again
+    fn list_session(&mut self, info: session::SessionInfo) {
+        self.session_list.retain(|s| s.id != info.id);
+        let at = self.session_list.iter()
+            .position(|s| s.updated <= info.updated)
+            .unwrap_or(self.session_list.len());
+        self.session_list.insert(at, info);
+    }
+
+    fn new_session(&mut self) {
+        let provider = self.s_ai_chat.as_ref().map(|e| e.name.clone()).unwrap_or_default();
+        self.session = session::Session::new(provider);
+        self.clear_result();
+        self.clear_compare();
+        self.context_usage = None;
+        self.edit_at = None;
+        self.found_at = None;
+    }
+
+    /// Indexes the session shown for the search, after it is saved
+    fn index_session(&mut self) {
+        self.search_index.update(&self.session);
+        self.save_search_index();
+    }
+
+    fn save_search_index(&self) {
+        if let Err(e) = self.search_index.save(search::INDEX_FILE) {
+            error!("Cannot save the search index: {}", e.to_string());
+        }
+    }
+
+    /// Asks for the title and tags of the session after its first answer, apart from the chat worker
+    fn ask_title(&mut self) -> iced::Task<Message> {
+        if !self.titles.enabled || !self.session.needs_title() || self.title_pending.contains(&self.session.id) {
+            return iced::Task::none();
+        }
+        let api = self.titles.chat.as_ref()
+            .and_then(|n| self.s_ai_table.values().find(|a| a.name == *n))
+            .or(self.s_ai_chat.as_ref())
+            .filter(|a| a.is_chat())
+            .cloned();
+        let Some(api) = api else {
+            return iced::Task::none();
+        };
+        let id = self.session.id.clone();
+        self.title_pending.insert(id.clone());
+        let messages = self.session.messages.clone();
+        iced::Task::perform(titles::suggest(api, messages), move |r| Message::Titled(id, r))
+    }
+
+    /// Language the transcriptions are translated to, None when they are used as spoken
+    fn translation_target(&self) -> Option<Language> {
+        let source = self.tr_language.unwrap_or(Language::EN);
+        Some(self.tr_target).filter(|t| self.translation.enabled && *t != source)
+    }
+
+    /// Whisper transcribes straight to English instead of the chat translating
+    fn whisper_translates(&self) -> bool {
+        self.translation_target() == Some(Language::EN) && self.translation.whisper_english
+    }
+
+    /// Asks the chat to translate the text of the last recording
+    fn translate_transcription(&mut self) -> iced::Task<Message> {
+        let text = self.transcription.as_ref().map(|t| t.text.trim().to_string());
+        let (Some(target), Some(text)) = (self.translation_target(), text) else {
+            return iced::Task::none();
+        };
+        let api = self.translation.chat.as_ref()
+            .and_then(|n| self.s_ai_table.values().find(|a| a.name == *n))
+            .or(self.s_ai_chat.as_ref())
+            .filter(|a| a.is_chat())
+            .cloned();
+        let Some(api) = api else {
+            self.display_av("Pick a chat to translate with in Settings");
+            return iced::Task::none();
+        };
+        let source = self.tr_language.unwrap_or(Language::EN);
+        self.translating = true;
+        iced::Task::perform(
+            translate::translate(api, text, source.name(), target.name()),
+            Message::Translated)
+    }
+
+    fn run_search(&mut self) {
+        self.search_hits = self.search_index.search(&self.search_filter);
+    }
+
+    /// Searches the documents when they are used and indexed
+    fn retriever(&self) -> Option<rag::Retriever> {
+        let index = self.rag_index.clone().filter(|_| self.rag.enabled)?;
+        Some(rag::Retriever::new(self.rag.clone(), index))
+    }
+
+    /// Shows the whole conversation in the result pane
+    fn show_session(&mut self) {
+        self.clear_result();
+        self.result_text.push_str(self.session.transcript().as_str());
+        self.result_raw = self.session.last_answer().map(|a| vec![a.to_string()]).unwrap_or_default();
+    }
+
+    /// Stores the branch shown and gives it to the chat worker
+    fn branch_changed(&mut self) -> iced::Task<Message> {
+        self.show_session();
+        self.context_usage = None;
+        self.session.updated = chrono::Utc::now();
+        if let Err(e) = self.sessions.save(&self.session) {
+            self.display_av(e.to_string());
+        }
+        self.index_session();
+        self.reload_sessions();
+        self.send_chat(vec![chat::ChatCommand::LoadHistory(self.session.messages.clone())])
+    }
+
+    /// Stops the answers being compared, what they streamed so far stays
+    fn abort_compare(&mut self) {
+        for h in self.compare_tasks.drain(..) {
+            h.abort();
+        }
+        for c in self.columns.iter_mut().filter(|c| !c.done) {
+            c.text.push_str("\n\n*[truncated]*");
+            c.done = true;
+        }
+    }
+
+    fn clear_compare(&mut self) {
+        self.abort_compare();
+        self.columns.clear();
+        self.compare_prompt = None;
+    }
+
+    /// Adds the measurements of an answer to the session and the usage report
+    fn record_stats(&mut self, s: usage::ExchangeStats) {
+        debug!("Stats: {:?}", s);
+        self.session.usage.add(&s);
+        self.usage_report.add(&s);
+        self.last_stats = Some(s);
+        if let Err(e) = self.usage_report.save(USAGE_FILE) {
+            error!("Cannot save usage report: {}", e.to_string());
+        }
+    }
+
+    /// Prompt of the session, or the default one
+    fn active_prompt(&self) -> Option<&config::Prompt> {
+        self.session.prompt.as_deref()
+            .and_then(|n| prompts::find(&self.prompts, n))
+            .or_else(|| self.default_prompt.as_deref().and_then(|n| prompts::find(&self.prompts, n)))
+    }
+
+    fn update_prompt_names(&mut self) {
+        self.s_prompts = combo_box::State::new(self.prompts.iter().map(|p| p.name.clone()).collect());
+    }
+
+    /// Sends the query with the expanded system prompt
+    fn ask(&mut self, clipboard: Option<String>) -> iced::Task<Message> {
+        let vars = prompts::PromptVars {
+            language: self.tr_language.map(|l| l.to_string()).unwrap_or_default(),
+            clipboard,
+            selection: self.query_text.selection(),
+        };
+        let context = self.active_prompt()
+            .map(|p| prompts::expand(p.text.as_str(), &vars))
+            .unwrap_or_default();
+        if let Some(pos) = self.regenerate.take() {
+            if self.s_ai_chat.is_none() || self.ai_cmd.is_none() {
+                return iced::Task::none();
+            }
+            self.session.truncate(pos);
+            self.clear_result();
+            self.clear_compare();
+            self.streaming = true;
+            return self.send_chat(vec![
+                chat::ChatCommand::SetContext(context),
+                chat::ChatCommand::LoadHistory(self.session.messages.clone()),
+                chat::ChatCommand::Regenerate,
+            ]);
+        }
+        let prompt = self.query_text.text();
+        if self.compare {
+            return self.ask_compare(context, prompt);
+        }
+        let Some(chat) = self.s_ai_chat.as_ref() else {
+            return iced::Task::none();
+        };
+        debug!("Asking AI: {}", chat.name);
+        let vision = chat.vision;
+        if self.ai_cmd.is_none() {
+            self.display_av("No channel to chat established");
+            return iced::Task::none();
+        }
+        if !self.images.is_empty() && !vision {
+            self.display_av(NO_VISION);
+            return iced::Task::none();
+        }
+        let images = self.images.drain(..).map(|a| a.to_image()).collect();
+        let files = std::mem::take(&mut self.files);
+        self.clear_result();
+        self.clear_compare();
+        self.streaming = true;
+        // Sent every time, a session may use no prompt at all
+        let mut cmds = vec![chat::ChatCommand::SetContext(context)];
+        if let Some(pos) = self.edit_at.take() {
+            self.session.truncate(pos);
+            cmds.push(chat::ChatCommand::LoadHistory(self.session.messages.clone()));
+        }
+        let transcription = self.transcription.take();
+        cmds.push(chat::ChatCommand::Prompt { text: prompt, images, files, transcription });
+        self.send_chat(cmds)
+    }
+
+    /// Names of the chats picked for comparison that do not accept images
+    fn blind_compared(&self) -> Vec<String> {
+        self.compare_sel.iter()
+            .filter(|n| !self.s_ai_table.values().any(|a| &a.name == *n && a.vision))
+            .cloned()
+            .collect()
+    }
+
+    /// Sends the query with the conversation so far to every chat picked for comparison at once
+    fn ask_compare(&mut self, context: String, text: String) -> iced::Task<Message> {
+        let apis: Vec<config::AiApi> = self.compare_sel.iter()
+            .filter_map(|n| self.s_ai_table.values().find(|a| &a.name == n))
+            .cloned()
+            .collect();
+        if apis.len() < 2 {
+            self.display_av("Pick at least two chats to compare");
+            return iced::Task::none();
+        }
+        if !self.images.is_empty() {
+            let blind: Vec<&str> = apis.iter()
+                .filter(|a| !a.vision)
+                .map(|a| a.name.as_str())
+                .collect();
+            if blind.len() == apis.len() {
+                self.display_av(NO_VISION);
+                return iced::Task::none();
+            }
+            if !blind.is_empty() {
+                self.display_av(format!("{} do not accept images, they get the prompt without them. Mark them with Accepts images in Settings if their models support them.", blind.join(", ")));
+            }
+        }
+        let images = self.images.drain(..).map(|a| a.to_image()).collect();
+        let files = std::mem::take(&mut self.files);
+        let transcription = self.transcription.take();
+        let prompt = chat::ChatMessage { images, files, transcription, ..chat::message(ChatCompletionMessageRole::User, text) };
+        if let Some(pos) = self.edit_at.take() {
+            self.session.truncate(pos);
+        }
+        let mut history = self.session.messages.clone();
+        history.push(prompt.clone());
+        let mut blind_history = history.clone();
+        if let Some(p) = blind_history.last_mut() {
+            p.images.clear();
+        }
+
+        self.clear_result();
+        self.clear_compare();
+        let mut tasks = vec![];
+        for (i, api) in apis.into_iter().enumerate() {
+            debug!("Comparing with: {}", api.name);
+            let history = if api.vision { &history } else { &blind_history };
+            let messages = compare::prepare(&api, context.as_str(), history);
+            let answer = chat::answer(api.clone(), messages);
+            let (task, handle) = iced::Task::sip(answer,
+                move |c| Message::CompareChunk(i, c),
+                move |r| Message::CompareDone(i, r))
+                .abortable();
+            self.columns.push(compare::Column::new(api));
+            self.compare_tasks.push(handle);
+            tasks.push(task);
+        }
+        self.compare_prompt = Some(prompt);
+        self.streaming = true;
+        iced::Task::batch(tasks)
+    }
+
+    fn clear_result(&mut self) {
+        self.result_raw.clear();
+        self.result_text = markdown::Content::new();
+        self.reasoning_raw.clear();
+        self.reasoning_text = markdown::Content::new();
+    }
+
     pub fn update(&mut self, message: Message) -> iced::Task<Message> {
         match message {
             Message::ToggleRecord => {
@@ -517,15 +1749,36 @@ impl AiVChat {
                     let au = self.audio_data.clone();
                     let lang = self.tr_language.unwrap_or(Language::EN);
                     let c = self.config.clone();
+                    let dir = self.recordings_dir.trim().to_string();
+                    let rate = self.sample_rate;
+                    let whisper_english = self.whisper_translates();
                     return iced::Task::perform(async move {
                         let model = c.read().await.tr_model.clone();
                         let lang = lang.as_str();
-                        transcribe::au_to_text(au, lang, model.as_str()).await
-                    }, |s| {
-                        match s {
-                            Ok(s) => Message::SetText(s),
-                            Err(e) => Message::ShowError(e.to_string()), // TODO: Add error dialog
-                        }
+                        let audio = if dir.is_empty() {
+                            None
+                        } else {
+                            let name = format!("{}.wav", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+                            let path = PathBuf::from(dir).join(name);
+                            let samples = au.read().await.clone();
+                            match transcribe::save_wav(&path, &samples, rate) {
+                                Ok(_) => Some(path.display().to_string()),
+                                Err(e) => {
+                                    error!("Cannot save the recording: {}", e.to_string());
+                                    None
+                                }
+                            }
+                        };
+                        let text = transcribe::au_to_text(au.clone(), lang, model.as_str(), false).await?;
+                        // The text as spoken is kept beside the English one
+                        let translation = if whisper_english {
+                            Some(transcribe::au_to_text(au, lang, model.as_str(), true).await?)
+                        } else {
+                            None
+                        };
+                        Ok(transcribe::Transcription { text, audio, translation })
+                    }, |r: anyhow::Result<transcribe::Transcription>| {
+                        Message::Transcribed(r.map_err(|e| e.to_string()))
                     });
                 }
                 iced::Task::none()
@@ -538,83 +1791,322 @@ impl AiVChat {
                 self.query_text = text_editor::Content::with_text(s.as_str());
                 iced::Task::none()
             }
-            Message::EditAction(a) => {
-                match a {
-                    text_editor::Action::Select(_) | text_editor::Action::Drag(_) => {
-                        self.query_text.perform(a);
-                        self.result_text = markdown::Content::new();
-                    }
-                    _ => self.query_text.perform(a),
+            Message::Transcribed(Ok(t)) => {
+                let translate = t.translation.is_none() && self.translation_target().is_some() && !self.whisper_translates();
+                let shown = t.translation.as_ref().unwrap_or(&t.text);
+                self.query_text = text_editor::Content::with_text(shown.as_str());
+                self.transcription = Some(t);
+                if translate {
+                    return self.translate_transcription();
                 }
                 iced::Task::none()
             }
-            Message::LinkClicked(e) => {
-                if let Err(e) = webbrowser::open(e.as_str()) {
-                    self.display_av(e.to_string());
-                }
-                iced::clipboard::write(e.to_string())
-            }
-            Message::DeviceSelected(d) => {
-                self.audio_data.blocking_write().clear();
-                self.device_sel = Some(d.clone());
-                if let Some(idx) = self.devices.options().iter().position(|x| x == &d) {
-                    debug!("Device index: {}", idx);
-                    self.device_i = idx as i32;
-                    if let Some(rs) = self.rs_sender.as_mut() {
-                        let mut rs = rs.clone();
-                        let i = self.device_i;
-                        return iced::Task::perform(async move {
-                            rs.send(RecState::Recording(i)).await
-                        }, |e| {
-                            match e {
-                                Ok(_) => Message::Void,
-                                Err(e) => Message::ShowError(e.to_string()),
+            Message::Translated(r) => {
+                self.translating = false;
+                match r {
+                    Ok(t) => {
+                        self.usage_report.add(&t.stats);
+                        if let Err(e) = self.usage_report.save(USAGE_FILE) {
+                            error!("Cannot save usage report: {}", e.to_string());
+                        }
+                        // Gone when the prompt was sent before the translation came
+                        if let Some(tr) = self.transcription.as_mut() {
+                            // The text is left alone when it was edited in the meantime
+                            if self.query_text.text().trim() == tr.text.trim() {
+                                self.query_text = text_editor::Content::with_text(t.text.as_str());
                             }
-                        });
+                            tr.translation = Some(t.text);
+                        }
                     }
-                } else {
-                    debug!("Device index not found");
-                    self.device_i = -1;
+                    Err(e) => self.display_av(format!("Cannot translate: {}", e)),
                 }
-
                 iced::Task::none()
             }
-            Message::AppendResult(s) => {
-                self.result_text.push_str(s.as_str());
+            Message::TranslateToggle(on) => {
+                self.translation.enabled = on;
                 iced::Task::none()
             }
-            Message::PlayToggle(p) => {
-                self.play = p;
+            Message::TranslateTargetSelected(lang) => {
+                self.tr_target = lang;
                 iced::Task::none()
             }
-            Message::ToggleSettings => {
-                self.settings = !self.settings;
-                self.new_chat = false;
+            Message::TranslateChatSelected(api) => {
+                self.translation.chat = Some(api.name);
                 iced::Task::none()
             }
-            Message::ChatSelected(s) => {
-                self.s_ai_chat = Some(s.clone());
+            Message::TranslateChatOfSession => {
+                self.translation.chat = None;
                 iced::Task::none()
             }
-            Message::NewAiChat => {
-                self.s_ai_chat = Some(config::AiApi::default());
-                self.new_chat = true;
+            Message::WhisperEnglishToggle(on) => {
+                self.translation.whisper_english = on;
                 iced::Task::none()
             }
-            Message::SaveSettings => {
-                self.new_chat = false;
-                let c = self.config.clone();
-                let sel = self.device_sel.clone();
-                let fsize = self.font_size_u;
-                let chat = self.s_ai_chat.clone();
-                let chat_ctx = self.prompt_context.clone();
-                let api_n = if let Some(chat) = &chat {
+            Message::Transcribed(Err(e)) => {
+                self.display_av(e);
+                iced::Task::none()
+            }
+            Message::RecordingsDirChanged(s) => {
+                self.recordings_dir = s;
+                iced::Task::none()
+            }
+            Message::TitlesToggle(on) => {
+                self.titles.enabled = on;
+                iced::Task::none()
+            }
+            Message::TitleChatSelected(api) => {
+                self.titles.chat = Some(api.name);
+                iced::Task::none()
+            }
+            Message::TitleChatOfSession => {
+                self.titles.chat = None;
+                iced::Task::none()
+            }
+            Message::Titled(id, r) => {
+                self.title_pending.remove(&id);
+                let t = match r {
+                    Ok(t) => t,
+                    Err(e) => {
+                        // The session keeps its first line as the title, it is asked again after the next answer
+                        error!("Cannot get a title for session {}: {}", id, e.to_string());
+                        return iced::Task::none();
+                    }
+                };
+                self.usage_report.add(&t.stats);
+                if let Err(e) = self.usage_report.save(USAGE_FILE) {
+                    error!("Cannot save usage report: {}", e.to_string());
+                }
+                let current = id == self.session.id;
+                let mut s = if current {
+                    self.session.clone()
+                } else {
+                    match self.sessions.load(&id) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            error!("Cannot load session {}: {}", id, e.to_string());
+                            return iced::Task::none();
+                        }
+                    }
+                };
+                // Renamed by the user in the meantime
+                if s.titled {
+                    return iced::Task::none();
+                }
+                s.title = t.title;
+                s.tags = t.tags;
+                s.titled = true;
+                if let Err(e) = self.sessions.save(&s) {
+                    error!("Cannot save session: {}", e.to_string());
+                }
+                self.search_index.update(&s);
+                self.save_search_index();
+                self.list_session(s.info());
+                if current {
+                    self.session = s;
+                }
+                iced::Task::none()
+            }
+            Message::Export => {
+                let session = self.session.clone();
+                let api = self.s_ai_table.values().find(|a| a.name == session.provider).cloned();
+                let prompt = self.active_prompt().cloned();
+                let name = export::file_name(&session, export::ExportFormat::Markdown);
+                iced::Task::perform(async move {
+                    let Some(f) = rfd::AsyncFileDialog::new()
+                        .add_filter("Markdown", &["md"])
+                        .add_filter("JSON", &["json"])
+                        .add_filter("HTML", &["html"])
+                        .set_file_name(name)
+                        .save_file()
+                        .await else {
+                        return Ok(String::new());
+                    };
+                    let path = f.path().to_path_buf();
+                    let format = export::ExportFormat::from_path(&path);
+                    let s = export::export(&session, api.as_ref(), prompt.as_ref(), format).map_err(|e| e.to_string())?;
+                    tokio::fs::write(&path, s).await.map_err(|e| e.to_string())?;
+                    Ok(path.display().to_string())
+                }, Message::Exported)
+            }
+            Message::Import => {
+                let store = self.sessions.clone();
+                iced::Task::perform(async move {
+                    let Some(f) = rfd::AsyncFileDialog::new()
+                        .add_filter("ChatGPT or Open WebUI export", &["json"])
+                        .pick_file()
+                        .await else {
+                        return Ok(0);
+                    };
+                    import::import(f.path(), &store).await
+                        .map_err(|e| e.to_string())
+                }, Message::Imported)
+            }
+            Message::Imported(Ok(n)) => {
+                if n > 0 {
+                    match self.search_index.sync(&self.sessions) {
+                        Ok(_) => self.save_search_index(),
+                        Err(e) => error!("Cannot index sessions: {}", e.to_string()),
+                    }
+                    self.reload_sessions();
+                    self.display_av(format!("Imported {} conversations", n));
+                }
+                iced::Task::none()
+            }
+            Message::Imported(Err(e)) => {
+                self.display_av(format!("Cannot import: {}", e));
+                iced::Task::none()
+            }
+            Message::Exported(Ok(path)) => {
+                if !path.is_empty() {
+                    info!("Exported to {}", path);
+                    self.display_av(format!("Exported to {}", path));
+                }
+                iced::Task::none()
+            }
+            Message::Exported(Err(e)) => {
+                self.display_av(format!("Cannot export: {}", e));
+                iced::Task::none()
+            }
+            Message::EditAction(a) => {
+                match a {
+                    text_editor::Action::Select(_) | text_editor::Action::Drag(_) => {
+                        self.query_text.perform(a);
+                        self.result_text = markdown::Content::new();
+                    }
+                    _ => self.query_text.perform(a),
+                }
+                iced::Task::none()
+            }
+            Message::LinkClicked(e) => {
+                if let Err(e) = webbrowser::open(e.as_str()) {
+                    self.display_av(e.to_string());
+                }
+                iced::clipboard::write(e.to_string())
+            }
+            Message::DeviceSelected(d) => {
+                self.audio_data.blocking_write().clear();
+                self.device_sel = Some(d.clone());
+                if let Some(idx) = self.devices.options().iter().position(|x| x == &d) {
+                    debug!("Device index: {}", idx);
+                    self.device_i = idx as i32;
+                    if let Some(rs) = self.rs_sender.as_mut() {
+                        let mut rs = rs.clone();
+                        let i = self.device_i;
+                        return iced::Task::perform(async move {
+                            rs.send(RecState::Recording(i)).await
+                        }, |e| {
+                            match e {
+                                Ok(_) => Message::Void,
+                                Err(e) => Message::ShowError(e.to_string()),
+                            }
+                        });
+                    }
+                } else {
+                    debug!("Device index not found");
+                    self.device_i = -1;
+                }
+
+                iced::Task::none()
+            }
+            Message::AppendResult(s) => {
+                self.result_text.push_str(s.as_str());
+                iced::Task::none()
+            }
+            Message::PlayToggle(p) => {
+                self.play = p;
+                iced::Task::none()
+            }
+            Message::ToggleSettings => {
+                self.settings = !self.settings;
+                self.new_chat = false;
+                iced::Task::none()
+            }
+            Message::ChatSelected(s) => {
+                self.s_models = combo_box::State::new(vec![]);
+                self.ollama_progress.clear();
+                let ollama = s.kind == config::BackendKind::Ollama;
+                self.gen_inputs = gen_inputs(Some(&s));
+                self.price_inputs = price_inputs(Some(&s));
+                self.s_ai_chat = Some(s.clone());
+                if ollama {
+                    return self.fetch_models();
+                }
+                iced::Task::none()
+            }
+            Message::NewAiChat => {
+                self.s_ai_chat = Some(config::AiApi::default());
+                self.gen_inputs = gen_inputs(None);
+                self.price_inputs = price_inputs(None);
+                self.new_chat = true;
+                iced::Task::none()
+            }
+            Message::SaveSettings => {
+                let Some(api) = self.s_ai_chat.clone() else {
+                    return self.update(Message::ModelValidated(Ok(())));
+                };
+                // Voices are not chat models and the mock answers with any model
+                if !api.is_chat() || api.kind == config::BackendKind::Mock {
+                    return self.update(Message::ModelValidated(Ok(())));
+                }
+                iced::Task::perform(async move {
+                    if api.model.trim().is_empty() {
+                        return Err(String::from("No model selected"));
+                    }
+                    match backend::from_api(&api).models().await {
+                        Ok(models) if !backend::offers(&models, &api.model) => {
+                            Err(format!("Model {} is not offered by {}", api.model, api.name))
+                        }
+                        Ok(_) => Ok(()),
+                        Err(e) => {
+                            // Not every provider lists its models, that should not prevent saving
+                            error!("Cannot validate model: {}", e.to_string());
+                            Ok(())
+                        }
+                    }
+                }, Message::ModelValidated)
+            }
+            Message::ModelValidated(Err(e)) => {
+                self.display_av(e);
+                iced::Task::none()
+            }
+            Message::ModelValidated(Ok(_)) => {
+                self.new_chat = false;
+                let c = self.config.clone();
+                let sel = self.device_sel.clone();
+                let fsize = self.font_size_u;
+                let chat = self.s_ai_chat.clone();
+                let prompts = self.prompts.clone();
+                let default_prompt = self.default_prompt.clone();
+                let rag = Some(self.rag.clone()).filter(|r| !r.folder.is_empty());
+                let recordings_dir = Some(self.recordings_dir.trim().to_string()).filter(|d| !d.is_empty());
+                let titles = self.titles.clone();
+                let mut translation = self.translation.clone();
+                translation.target = self.tr_target.to_string();
+                let api_n = if let Some(chat) = &chat {
                     self.s_ai_table.iter()
                         .find(|(_,v)| v.name.eq(&chat.name))
                         .map(|(k,_)| k.clone())
                 } else {
                     None
                 };
+                // Sessions, comparison, titles and translation take the providers from the table
+                let api_n = match (api_n, chat.clone()) {
+                    (Some(api_n), Some(chat)) => {
+                        self.s_ai_table.insert(api_n.clone(), chat);
+                        Some(api_n)
+                    }
+                    (Some(api_n), None) => {
+                        self.s_ai_table.remove(&api_n);
+                        Some(api_n)
+                    }
+                    (None, Some(chat)) => {
+                        let new_id = uuid::Uuid::now_v1(&[21, 2, 31, 52, 0, 61]).to_string();
+                        self.s_ai_table.insert(new_id.clone(), chat);
+                        Some(new_id)
+                    }
+                    (None, None) => None,
+                };
+                self.s_ai_chats = combo_box::State::new(self.s_ai_table.values().cloned().collect());
 
                 let theme = if let Some(t) = self.theme.clone() {
                     t.to_string()
@@ -623,31 +2115,31 @@ impl AiVChat {
                 };
 
                 let lang = self.tr_language.unwrap_or(Language::PL).to_string();
-                iced::Task::perform(async move {
+                // The worker keeps its own copy of the provider, so it has to get the edited one
+                let mut cmds = vec![chat::ChatCommand::SetRetriever(self.retriever())];
+                if let Some(chat) = chat.clone().filter(|c| c.is_chat()) {
+                    cmds.insert(0, chat::ChatCommand::SetChat(chat));
+                }
+                let set_chat = self.send_chat(cmds);
+                let save = iced::Task::perform(async move {
                     let mut config = c.write().await;
                     config.rec_device = sel;
                     config.font_size = fsize;
                     config.theme = theme;
-                    let sel_chat = chat.as_ref().map(|e| e.name.clone());
-                    config.sel_chat = if sel_chat == Some("Elevenlabs".to_string()) {
-                        None
-                    } else {
-                        sel_chat
-                    };
-                    config.prompt_context = Some(chat_ctx);
+                    config.sel_chat = chat.as_ref().filter(|e| e.is_chat()).map(|e| e.name.clone());
+                    config.prompt_context = None;
+                    config.prompts = prompts;
+                    config.sel_prompt = default_prompt;
+                    config.rag = rag;
+                    config.recordings_dir = recordings_dir;
+                    config.titles = Some(titles);
+                    config.translation = Some(translation);
                     if let Some(api_n) = api_n {
                         if let Some(chat) = chat {
-                            config.ai_chats.entry(api_n)
-                                .and_modify(|e| *e = chat);
+                            config.ai_chats.insert(api_n, chat);
                         } else {
                             config.ai_chats.remove(&api_n);
                         }
-                    } else {
-                        if let Some(chat) = chat {
-                            let new_id = uuid::Uuid::now_v1(&[21, 2, 31, 52, 0, 61]);
-                            config.ai_chats.entry(new_id.to_string())
-                                .insert_entry(chat);
-                        }
                     }
                     config.tr_lang = lang;
                     if let Ok(s_conf) = toml::to_string(&config.clone()) {
@@ -658,7 +2150,8 @@ impl AiVChat {
                     }
                 }, |_| {
                     Message::ToggleSettings
-                })
+                });
+                iced::Task::batch([save, set_chat])
             }
             Message::FontSizeChangedUp => {
                 self.font_size_u += 1;
@@ -680,27 +2173,91 @@ impl AiVChat {
             Message::ChatEventReceived(e) => {
                 match e {
                     chat::ChatEvent::ChatReady(r) => {
-                        let mut s = r.clone();
                         self.ai_cmd = Some(r);
+                        let mut cmds = vec![];
                         if let Some(ai_api) = self.s_ai_chat.as_ref() {
-                            let api = ai_api.clone();
-                            return iced::Task::perform(async move {
-                                    s.send(chat::ChatCommand::SetChat(api)).await
-                                }, |res| {
-                                    if let Err(e) = res {
-                                        Message::ShowError(e.to_string())
-                                    } else {
-                                        Message::Void
-                                    }
-                            });
+                            cmds.push(chat::ChatCommand::SetChat(ai_api.clone()));
                         }
+                        cmds.push(chat::ChatCommand::SetRetriever(self.retriever()));
+                        return self.send_chat(cmds);
+                    }
+                    chat::ChatEvent::Sources(s) => {
+                        self.sources = s;
                     }
                     chat::ChatEvent::StreamEnded => {
+                        if !self.sources.is_empty() {
+                            // Kept out of result_raw, the links are not part of the answer
+                            self.result_text.push_str("\n\n**Sources**\n\n");
+                            for s in self.sources.drain(..) {
+                                self.result_text.push_str(format!("{}. {}\n", s.n, s.link()).as_str());
+                            }
+                        }
+                        self.streaming = false;
+                        self.pending_tool = None;
+                        self.retry_note = None;
+                    }
+                    chat::ChatEvent::ContextUsage { used, limit, left_out } => {
+                        self.context_usage = Some((used, limit, left_out));
+                    }
+                    chat::ChatEvent::Retrying { error, attempt, delay } => {
+                        self.retry_note = Some(format!("{}, retrying in {}s (attempt {})", error, delay.as_secs_f32().ceil(), attempt));
+                    }
+                    chat::ChatEvent::ToolRequest(call) => {
+                        if self.allowed_tools.contains(&call.name) {
+                            return self.send_chat(vec![chat::ChatCommand::ToolDecision { id: call.id, allow: true }]);
+                        }
+                        self.pending_tool = Some(call);
+                    }
+                    chat::ChatEvent::ToolDone { call, allowed } => {
+                        let outcome = if allowed { "" } else { ", denied" };
+                        self.result_text.push_str(format!("\n\n*Tool: {}({}){}*\n\n", call.name, call.arguments, outcome).as_str());
+                    }
+                    chat::ChatEvent::Reasoning(r) => {
+                        // Kept out of result_raw so that it is neither copied nor read aloud
+                        self.reasoning_text.push_str(r.as_str());
+                        self.reasoning_raw.push_str(r.as_str());
+                    }
+                    chat::ChatEvent::Truncated => {
+                        // A new prompt cuts the previous answer off after its pane was cleared
+                        if !self.result_raw.is_empty() {
+                            self.result_text.push_str("\n\n*[truncated]*");
+                        }
+                    }
+                    chat::ChatEvent::Stats(s) => {
+                        self.record_stats(s);
+                    }
+                    chat::ChatEvent::HistoryUpdated(h) => {
+                        self.session.update(h);
+                        self.session.updated = chrono::Utc::now();
+                        if let Some(api) = self.s_ai_chat.as_ref() {
+                            self.session.provider = api.name.clone();
+                        }
+                        self.session.default_title();
+                        self.index_session();
+                        self.list_session(self.session.info());
+                        let title = self.ask_title();
+                        let store = self.sessions.clone();
+                        let session = self.session.clone();
+                        let save = iced::Task::perform(async move {
+                            store.save(&session)
+                        }, |r| {
+                            match r {
+                                Ok(_) => Message::Void,
+                                Err(e) => Message::ShowError(e.to_string()),
+                            }
+                        });
+                        return iced::Task::batch([save, title]);
                     }
                     chat::ChatEvent::ChatError(e) => {
-                        self.display_av(e);
+                        let hint = e.hint();
+                        if hint.is_empty() {
+                            self.display_av(e.to_string());
+                        } else {
+                            self.display_av(format!("{}\n\n{}", e, hint));
+                        }
                     }
                     chat::ChatEvent::ChatMessage(m) => {
+                        self.retry_note = None;
                         self.result_text.push_str(m.as_str());
                         let d = format!("{}", m.as_str());
                         self.result_raw.push(m);
@@ -761,6 +2318,7 @@ impl AiVChat {
                     RecEvent::SetSampleRate(sr) => {
                         debug!("New SR: {}", sr);
                         self.vm.sample_rate(sr);
+                        self.sample_rate = sr as u32;
                     }
                 }
 
@@ -774,39 +2332,170 @@ impl AiVChat {
                 self.display_av(e.as_str());
                 iced::Task::none()
             }
-            Message::CtxInput(s) => {
-                self.prompt_context = s;
+            Message::AskChat => {
+                if self.s_ai_chat.is_none() && !self.compare {
+                    return iced::Task::none();
+                }
+                if self.active_prompt().is_some_and(|p| prompts::needs_clipboard(p.text.as_str())) {
+                    return iced::clipboard::read().map(Message::AskWithClipboard);
+                }
+                self.ask(None)
+            }
+            Message::AskWithClipboard(clipboard) => {
+                self.ask(clipboard)
+            }
+            Message::PromptPicked(name) => {
+                self.prompt_edit = self.prompts.iter().position(|p| p.name == name);
+                let text = self.prompt_edit.map(|i| self.prompts[i].text.clone()).unwrap_or_default();
+                self.prompt_editor = text_editor::Content::with_text(text.as_str());
                 iced::Task::none()
             }
-            Message::AskChat => {
-                if self.s_ai_chat.is_none() {
+            Message::NewPrompt => {
+                let name = prompts::new_name(&self.prompts);
+                self.prompts.push(config::Prompt { name, text: String::new() });
+                self.prompt_edit = Some(self.prompts.len() - 1);
+                self.prompt_editor = text_editor::Content::new();
+                self.update_prompt_names();
+                iced::Task::none()
+            }
+            Message::DeletePrompt => {
+                if let Some(i) = self.prompt_edit.take() {
+                    let p = self.prompts.remove(i);
+                    if self.default_prompt.as_ref() == Some(&p.name) {
+                        self.default_prompt = None;
+                    }
+                    self.prompt_editor = text_editor::Content::new();
+                    self.update_prompt_names();
+                }
+                iced::Task::none()
+            }
+            Message::PromptNameChanged(name) => {
+                if let Some(p) = self.prompt_edit.and_then(|i| self.prompts.get_mut(i)) {
+                    if self.default_prompt.as_ref() == Some(&p.name) {
+                        self.default_prompt = Some(name.clone());
+                    }
+                    p.name = name;
+                }
+                self.update_prompt_names();
+                iced::Task::none()
+            }
+            Message::PromptEditAction(a) => {
+                self.prompt_editor.perform(a);
+                if let Some(p) = self.prompt_edit.and_then(|i| self.prompts.get_mut(i)) {
+                    p.text = self.prompt_editor.text();
+                }
+                iced::Task::none()
+            }
+            Message::DefaultPromptSelected(name) => {
+                self.default_prompt = Some(name);
+                iced::Task::none()
+            }
+            Message::SessionPromptSelected(name) => {
+                self.session.prompt = Some(name);
+                if !self.session.is_empty() {
+                    if let Err(e) = self.sessions.save(&self.session) {
+                        error!("Cannot save session: {}", e.to_string());
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::ToggleReasoning => {
+                self.show_reasoning = !self.show_reasoning;
+                iced::Task::none()
+            }
+            Message::StopChat => {
+                if !self.compare_tasks.is_empty() {
+                    self.abort_compare();
+                    self.streaming = false;
                     return iced::Task::none();
                 }
-                let chat = self.s_ai_chat.clone().unwrap();
-                debug!("Asking AI: {}", chat.name);
-                self.result_raw.clear();
-                self.result_text = markdown::Content::new();
-                let prompt = self.query_text.text();
-                let context = self.prompt_context.clone();
-                if let Some(mut cmd) = self.ai_cmd.clone() {
-                    iced::Task::perform(async move {
-                        if !context.is_empty() {
-                            if let Err(e) = cmd.send(chat::ChatCommand::SetContext(context)).await {
-                                return Err(e)
-                            }
+                if !self.streaming {
+                    return iced::Task::none();
+                }
+                self.send_chat(vec![chat::ChatCommand::Stop])
+            }
+            Message::NewConversation => {
+                self.streaming = false;
+                self.new_session();
+                self.send_chat(vec![chat::ChatCommand::NewConversation])
+            }
+            Message::OpenSession(id) => {
+                match self.sessions.load(&id) {
+                    Ok(s) => {
+                        let mut cmds = vec![];
+                        if let Some(api) = self.s_ai_table.values().find(|e| e.name == s.provider) {
+                            self.gen_inputs = gen_inputs(Some(api));
+                            self.price_inputs = price_inputs(Some(api));
+                            self.s_ai_chat = Some(api.clone());
+                            cmds.push(chat::ChatCommand::SetChat(api.clone()));
                         }
-                        cmd.send(chat::ChatCommand::Prompt(prompt)).await
-                    }, |e| {
-                        if let Err(e) = e {
-                            Message::ShowError(e.to_string())
-                        } else {
-                            Message::Void
+                        cmds.push(chat::ChatCommand::LoadHistory(s.messages.clone()));
+                        self.context_usage = None;
+                        self.clear_compare();
+                        self.edit_at = None;
+                        self.found_at = None;
+                        self.session = s;
+                        self.show_session();
+                        self.send_chat(cmds)
+                    }
+                    Err(e) => {
+                        self.display_av(e.to_string());
+                        iced::Task::none()
+                    }
+                }
+            }
+            Message::StartRename(id) => {
+                let info = self.session_list.iter().find(|s| s.id == id);
+                self.rename_text = info.map(|s| s.title.clone()).unwrap_or_default();
+                self.rename_tags = info.map(|s| s.tags.join(", ")).unwrap_or_default();
+                self.rename_id = Some(id);
+                iced::Task::none()
+            }
+            Message::RenameInput(s) => {
+                self.rename_text = s;
+                iced::Task::none()
+            }
+            Message::RenameTagsInput(s) => {
+                self.rename_tags = s;
+                iced::Task::none()
+            }
+            Message::RenameSubmit => {
+                if let Some(id) = self.rename_id.take() {
+                    let mut tags: Vec<String> = vec![];
+                    for t in self.rename_tags.split(',') {
+                        let t = t.trim().trim_start_matches('#').to_lowercase();
+                        if !t.is_empty() && !tags.contains(&t) {
+                            tags.push(t);
                         }
-                    })
-                } else {
-                    self.display_av("No channel to chat established");
-                    iced::Task::none()
+                    }
+                    match self.sessions.rename(&id, self.rename_text.trim(), tags) {
+                        Ok(s) => {
+                            self.search_index.update(&s);
+                            self.save_search_index();
+                            if s.id == self.session.id {
+                                self.session.title = s.title;
+                                self.session.tags = s.tags;
+                                self.session.titled = true;
+                            }
+                        }
+                        Err(e) => self.display_av(e.to_string()),
+                    }
+                    self.reload_sessions();
                 }
+                iced::Task::none()
+            }
+            Message::DeleteSession(id) => {
+                if let Err(e) = self.sessions.delete(&id) {
+                    self.display_av(e.to_string());
+                }
+                self.search_index.remove(&id);
+                self.save_search_index();
+                self.reload_sessions();
+                if id == self.session.id {
+                    self.new_session();
+                    return self.send_chat(vec![chat::ChatCommand::NewConversation]);
+                }
+                iced::Task::none()
             }
             Message::ChatApiKeyChanged(key) => {
                 debug!("Key changed: {}", key);
@@ -826,6 +2515,528 @@ impl AiVChat {
                     .map(|s| s.model = model);
                 iced::Task::none()
             }
+            Message::ToolsToggle(on) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    chat.tools = on;
+                }
+                iced::Task::none()
+            }
+            Message::VisionToggle(on) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    chat.vision = on;
+                }
+                iced::Task::none()
+            }
+            Message::AttachImage => {
+                iced::Task::perform(async {
+                    let files = rfd::AsyncFileDialog::new()
+                        .add_filter("Images", attachments::IMAGE_EXTENSIONS)
+                        .pick_files()
+                        .await
+                        .unwrap_or_default();
+                    let mut res = vec![];
+                    for f in files {
+                        res.push(attachments::load_image(f.path().to_path_buf()).await.map_err(|e| e.to_string())?);
+                    }
+                    Ok(res)
+                }, Message::ImagesAttached)
+            }
+            Message::PasteImage => {
+                iced::Task::perform(async {
+                    tokio::task::spawn_blocking(attachments::clipboard_image).await
+                        .map_err(|e| e.to_string())?
+                        .map(|a| vec![a])
+                        .map_err(|e| format!("No image in the clipboard: {}", e))
+                }, Message::ImagesAttached)
+            }
+            Message::FileDropped(path) => {
+                if attachments::image_mime(&path).is_none() {
+                    return iced::Task::perform(async move {
+                        attachments::load_file(path).await
+                            .map(|f| vec![f])
+                            .map_err(|e| e.to_string())
+                    }, Message::FilesAttached);
+                }
+                iced::Task::perform(async move {
+                    attachments::load_image(path).await
+                        .map(|a| vec![a])
+                        .map_err(|e| e.to_string())
+                }, Message::ImagesAttached)
+            }
+            Message::ImagesAttached(Ok(images)) => {
+                if images.is_empty() {
+                    return iced::Task::none();
+                }
+                if self.compare {
+                    // The images are only left out for the chats of the comparison that cannot see them
+                    let blind = self.blind_compared();
+                    if blind.len() == self.compare_sel.len() {
+                        self.display_av(NO_VISION);
+                        return iced::Task::none();
+                    }
+                    if !blind.is_empty() {
+                        self.display_av(format!("{} do not accept images, they get the prompt without them.", blind.join(", ")));
+                    }
+                } else if !self.s_ai_chat.as_ref().is_some_and(|c| c.vision) {
+                    self.display_av(NO_VISION);
+                    return iced::Task::none();
+                }
+                self.images.extend(images);
+                iced::Task::none()
+            }
+            Message::ImagesAttached(Err(e)) => {
+                self.display_av(e);
+                iced::Task::none()
+            }
+            Message::AttachFile => {
+                iced::Task::perform(async {
+                    let files = rfd::AsyncFileDialog::new()
+                        .pick_files()
+                        .await
+                        .unwrap_or_default();
+                    let mut res = vec![];
+                    for f in files {
+                        res.push(attachments::load_file(f.path().to_path_buf()).await.map_err(|e| e.to_string())?);
+                    }
+                    Ok(res)
+                }, Message::FilesAttached)
+            }
+            Message::FilesAttached(Ok(files)) => {
+                let cut: Vec<&str> = files.iter()
+                    .filter(|f| f.truncated)
+                    .map(|f| f.name.as_str())
+                    .collect();
+                if !cut.is_empty() {
+                    self.display_av(format!("Only the beginning of {} is attached, the file is too long", cut.join(", ")));
+                }
+                self.files.extend(files);
+                iced::Task::none()
+            }
+            Message::FilesAttached(Err(e)) => {
+                self.display_av(e);
+                iced::Task::none()
+            }
+            Message::RemoveFile(i) => {
+                if i < self.files.len() {
+                    self.files.remove(i);
+                }
+                iced::Task::none()
+            }
+            Message::RemoveImage(i) => {
+                if i < self.images.len() {
+                    self.images.remove(i);
+                }
+                iced::Task::none()
+            }
+            Message::ToolDecision(allow) => {
+                let Some(call) = self.pending_tool.take() else {
+                    return iced::Task::none();
+                };
+                self.send_chat(vec![chat::ChatCommand::ToolDecision { id: call.id, allow }])
+            }
+            Message::CompareToggle(on) => {
+                self.compare = on;
+                if on && self.compare_sel.is_empty() {
+                    self.compare_sel.extend(self.s_ai_chat.as_ref().map(|a| a.name.clone()));
+                }
+                iced::Task::none()
+            }
+            Message::CompareChatToggle(name, on) => {
+                self.compare_sel.retain(|n| *n != name);
+                if on {
+                    self.compare_sel.push(name);
+                }
+                iced::Task::none()
+            }
+            Message::CompareChunk(i, c) => {
+                if let Some(col) = self.columns.get_mut(i) {
+                    col.push(c.as_str());
+                }
+                iced::Task::none()
+            }
+            Message::CompareDone(i, r) => {
+                let Some(col) = self.columns.get_mut(i) else {
+                    return iced::Task::none();
+                };
+                match r {
+                    Ok(s) => {
+                        col.stats = Some(s.clone());
+                        col.done = true;
+                        self.record_stats(s);
+                    }
+                    Err(e) => {
+                        error!("{} failed: {}", col.api.name, e.to_string());
+                        col.fail(e);
+                    }
+                }
+                if self.columns.iter().all(|c| c.done) {
+                    self.compare_tasks.clear();
+                    self.streaming = false;
+                }
+                iced::Task::none()
+            }
+            Message::PickWinner(i) => {
+                let Some(col) = self.columns.get(i).filter(|c| c.can_win()) else {
+                    return iced::Task::none();
+                };
+                let Some(mut prompt) = self.compare_prompt.clone() else {
+                    return iced::Task::none();
+                };
+                let api = col.api.clone();
+                // Kept the way the chat saw it, so the images are not sent to it later
+                if !api.vision {
+                    prompt.images.clear();
+                }
+                let answer = col.answer.clone();
+                info!("Continuing with {}", api.name);
+                let mut history = self.session.messages.clone();
+                history.push(prompt);
+                history.push(chat::message(ChatCompletionMessageRole::Assistant, answer.clone()));
+                self.clear_compare();
+                self.compare = false;
+                self.streaming = false;
+                self.gen_inputs = gen_inputs(Some(&api));
+                self.price_inputs = price_inputs(Some(&api));
+                self.s_ai_chat = Some(api.clone());
+                self.clear_result();
+                self.result_text.push_str(answer.as_str());
+                self.result_raw.push(answer);
+                let load = self.send_chat(vec![
+                    chat::ChatCommand::SetChat(api),
+                    chat::ChatCommand::LoadHistory(history.clone()),
+                ]);
+                // Saved the way the worker's updates are
+                let save = self.update(Message::ChatEventReceived(chat::ChatEvent::HistoryUpdated(history)));
+                iced::Task::batch([load, save])
+            }
+            Message::Regenerate(pos) => {
+                if self.streaming || self.s_ai_chat.is_none() {
+                    return iced::Task::none();
+                }
+                self.regenerate = Some(pos);
+                self.update(Message::AskChat)
+            }
+            Message::EditMessage(pos) => {
+                let Some(m) = self.session.messages.get(pos) else {
+                    return iced::Task::none();
+                };
+                self.query_text = text_editor::Content::with_text(m.content.as_deref().unwrap_or_default());
+                self.files = m.files.clone();
+                self.transcription = m.transcription.clone();
+                self.images = m.images.iter()
+                    .filter_map(|i| attachments::ImageAttachment::from_image(i)
+                        .inspect_err(|e| error!("Cannot restore image: {}", e.to_string()))
+                        .ok())
+                    .collect();
+                self.edit_at = Some(pos);
+                iced::Task::none()
+            }
+            Message::CancelEdit => {
+                self.edit_at = None;
+                self.transcription = None;
+                self.query_text = text_editor::Content::new();
+                self.files.clear();
+                self.images.clear();
+                iced::Task::none()
+            }
+            Message::DeleteMessage(pos) => {
+                if self.streaming || pos >= self.session.messages.len() {
+                    return iced::Task::none();
+                }
+                self.edit_at = None;
+                self.session.truncate(pos);
+                self.branch_changed()
+            }
+            Message::SwitchBranch(pos, forward) => {
+                if self.streaming || !self.session.switch(pos, forward) {
+                    return iced::Task::none();
+                }
+                self.edit_at = None;
+                self.branch_changed()
+            }
+            Message::RagToggle(on) => {
+                self.rag.enabled = on;
+                iced::Task::none()
+            }
+            Message::RagFolderChanged(s) => {
+                self.rag.folder = s;
+                iced::Task::none()
+            }
+            Message::RagBrowse => {
+                iced::Task::perform(async {
+                    rfd::AsyncFileDialog::new()
+                        .pick_folder()
+                        .await
+                        .map(|f| f.path().display().to_string())
+                }, |f| f.map(Message::RagFolderChanged).unwrap_or(Message::Void))
+            }
+            Message::RagUrlChanged(s) => {
+                self.rag.url = s;
+                iced::Task::none()
+            }
+            Message::RagKeyChanged(s) => {
+                self.rag.key = s;
+                iced::Task::none()
+            }
+            Message::RagModelChanged(s) => {
+                self.rag.model = s;
+                iced::Task::none()
+            }
+            Message::RagTopKChanged(s) => {
+                if s.is_empty() {
+                    self.rag.top_k = None;
+                } else if let Ok(k) = s.parse::<usize>() {
+                    self.rag.top_k = Some(k);
+                }
+                iced::Task::none()
+            }
+            Message::RagIndex => {
+                self.rag_busy = true;
+                self.rag_progress = String::from("starting");
+                let build = rag::build(self.rag.clone(), self.rag_index.clone());
+                iced::Task::sip(build, Message::RagProgress, |r| {
+                    Message::RagIndexed(r.map_err(|e| e.to_string()))
+                })
+            }
+            Message::RagProgress(p) => {
+                self.rag_progress = p;
+                iced::Task::none()
+            }
+            Message::RagIndexed(r) => {
+                self.rag_busy = false;
+                match r {
+                    Ok(index) => {
+                        if let Err(e) = index.save(rag::INDEX_FILE) {
+                            self.display_av(format!("Cannot save the documents index: {}", e));
+                        }
+                        self.rag_progress = format!("{} files, {} passages", index.files(), index.chunks());
+                        if !index.failed.is_empty() {
+                            self.display_av(format!("Cannot index {}, see the log", index.failed.join(", ")));
+                        }
+                        self.rag_index = Some(Arc::new(index));
+                        return self.send_chat(vec![chat::ChatCommand::SetRetriever(self.retriever())]);
+                    }
+                    Err(e) => {
+                        self.rag_progress.clear();
+                        self.display_av(e);
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::ToolAlwaysAllow => {
+                let Some(call) = self.pending_tool.take() else {
+                    return iced::Task::none();
+                };
+                self.allowed_tools.insert(call.name);
+                self.send_chat(vec![chat::ChatCommand::ToolDecision { id: call.id, allow: true }])
+            }
+            Message::ChatApiKindChanged(kind) => {
+                debug!("Kind changed: {}", kind);
+                self.s_ai_chat.as_mut()
+                    .map(|s| s.kind = kind);
+                if kind == config::BackendKind::Ollama {
+                    return self.fetch_models();
+                }
+                iced::Task::none()
+            }
+            Message::GenParamChanged(p, v) => {
+                if let Some(i) = config::GenParam::ALL.iter().position(|g| *g == p) {
+                    self.gen_inputs[i] = v.clone();
+                }
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    let params = chat.params.get_or_insert_default();
+                    if !params.set(p, v.as_str()) {
+                        debug!("Invalid value for {}: {}", p.label(), v);
+                    }
+                    if params.is_empty() {
+                        chat.params = None;
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::PriceInChanged(v) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    set_price(chat, v.as_str(), true);
+                }
+                self.price_inputs[0] = v;
+                iced::Task::none()
+            }
+            Message::PriceOutChanged(v) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    set_price(chat, v.as_str(), false);
+                }
+                self.price_inputs[1] = v;
+                iced::Task::none()
+            }
+            Message::ToggleUsage => {
+                self.show_usage = !self.show_usage;
+                iced::Task::none()
+            }
+            Message::ToggleSearch => {
+                self.show_search = !self.show_search;
+                if self.show_search {
+                    self.s_search_providers = combo_box::State::new(self.search_index.providers());
+                    self.run_search();
+                }
+                iced::Task::none()
+            }
+            Message::SearchInput(s) => {
+                self.search_filter.query = s;
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::SearchProvider(p) => {
+                self.search_filter.provider = Some(p);
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::SearchAnyProvider => {
+                self.search_filter.provider = None;
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::SearchRole(r) => {
+                self.search_filter.role = r;
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::SearchFromChanged(s) => {
+                self.search_filter.from = chrono::NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok();
+                self.search_from = s;
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::SearchToChanged(s) => {
+                self.search_filter.to = chrono::NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok();
+                self.search_to = s;
+                self.run_search();
+                iced::Task::none()
+            }
+            Message::OpenSearchHit(id, pos) => {
+                let open = self.update(Message::OpenSession(id.clone()));
+                if self.session.id == id {
+                    self.show_search = false;
+                    self.found_at = Some(pos);
+                }
+                open
+            }
+            Message::FetchModels => {
+                self.fetch_models()
+            }
+            Message::ModelsFetched(r) => {
+                match r {
+                    Ok(models) => self.s_models = combo_box::State::new(models),
+                    Err(e) => self.display_av(e),
+                }
+                iced::Task::none()
+            }
+            Message::ModelSelected(model) => {
+                debug!("Model selected: {}", model);
+                self.s_ai_chat.as_mut()
+                    .map(|s| s.model = model);
+                iced::Task::none()
+            }
+            Message::OllamaPullInput(s) => {
+                self.ollama_pull = s;
+                iced::Task::none()
+            }
+            Message::OllamaPull => {
+                let Some(api) = self.s_ai_chat.clone() else {
+                    return iced::Task::none();
+                };
+                let model = self.ollama_pull.trim().to_string();
+                if model.is_empty() {
+                    return iced::Task::none();
+                }
+                self.ollama_progress = String::from("starting");
+                let pull = backend::ollama::OllamaBackend::new(api).pull(model);
+                iced::Task::sip(pull, Message::OllamaPullProgress, |r| {
+                    Message::OllamaPullDone(r.map_err(|e| e.to_string()))
+                })
+            }
+            Message::OllamaPullProgress(p) => {
+                self.ollama_progress = p.to_string();
+                iced::Task::none()
+            }
+            Message::OllamaPullDone(r) => {
+                match r {
+                    Ok(_) => {
+                        self.ollama_progress = String::from("done");
+                        self.ollama_pull.clear();
+                        return self.fetch_models();
+                    }
+                    Err(e) => {
+                        self.ollama_progress.clear();
+                        self.display_av(e);
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::OllamaDelete => {
+                let Some(api) = self.s_ai_chat.clone() else {
+                    return iced::Task::none();
+                };
+                iced::Task::perform(async move {
+                    let model = api.model.clone();
+                    backend::ollama::OllamaBackend::new(api).delete(model.as_str()).await
+                        .map_err(|e| e.to_string())
+                }, Message::OllamaDeleted)
+            }
+            Message::OllamaDeleted(r) => {
+                match r {
+                    Ok(_) => {
+                        self.s_ai_chat.as_mut()
+                            .map(|s| s.model.clear());
+                        return self.fetch_models();
+                    }
+                    Err(e) => self.display_av(e),
+                }
+                iced::Task::none()
+            }
+            Message::OllamaKeepAliveChanged(s) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    let ollama = chat.ollama.get_or_insert_default();
+                    ollama.keep_alive = if s.is_empty() { None } else { Some(s) };
+                }
+                iced::Task::none()
+            }
+            Message::ContextLengthChanged(s) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    if s.is_empty() {
+                        chat.context_length = None;
+                    } else if let Ok(n) = s.parse::<u64>() {
+                        chat.context_length = Some(n);
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::ContextPolicyChanged(p) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    chat.context_policy = p;
+                }
+                iced::Task::none()
+            }
+            Message::TimeoutChanged(s) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    if s.is_empty() {
+                        chat.timeout = None;
+                    } else if let Ok(t) = s.parse::<u64>() {
+                        chat.timeout = Some(t);
+                    }
+                }
+                iced::Task::none()
+            }
+            Message::OllamaNumCtxChanged(s) => {
+                if let Some(chat) = self.s_ai_chat.as_mut() {
+                    let ollama = chat.ollama.get_or_insert_default();
+                    if s.is_empty() {
+                        ollama.num_ctx = None;
+                    } else if let Ok(n) = s.parse::<u32>() {
+                        ollama.num_ctx = Some(n);
+                    }
+                }
+                iced::Task::none()
+            }
             Message::NewAiChatNameChanged(name) => {
                 debug!("Name changed: {}", name);
                 self.s_ai_chat.as_mut()
@@ -838,7 +3049,7 @@ impl AiVChat {
                     VoiceEvent::Ready(r) => {
                         self.v_sender = Some(r);
                         let el_api = self.s_ai_table.iter()
-                            .find(|(_,e)| e.name == String::from("Elevenlabs"));
+                            .find(|(_,e)| e.speech);
                         if let Some((_,el_api)) = el_api.as_ref() {
                             
                             let model = self.voices.get(&el_api.model).unwrap().clone();
@@ -880,6 +3091,41 @@ impl AiVChat {
     }
 }
 
+fn gen_inputs(api: Option<&config::AiApi>) -> Vec<String> {
+    let params = api.and_then(|a| a.params.clone()).unwrap_or_default();
+    config::GenParam::ALL.iter()
+        .map(|p| params.value(*p))
+        .collect()
+}
+
+fn price_inputs(api: Option<&config::AiApi>) -> [String; 2] {
+    match api.and_then(|a| a.pricing.as_ref()) {
+        Some(p) => [p.input.to_string(), p.output.to_string()],
+        None => [String::new(), String::new()],
+    }
+}
+
+/// Sets the input or output price, an empty value with no other price removes the pricing
+fn set_price(api: &mut config::AiApi, v: &str, input: bool) {
+    let v = v.trim();
+    let price = if v.is_empty() {
+        0.0
+    } else if let Ok(p) = v.parse::<f64>() {
+        p
+    } else {
+        return;
+    };
+    let pricing = api.pricing.get_or_insert_default();
+    if input {
+        pricing.input = price;
+    } else {
+        pricing.output = price;
+    }
+    if pricing.input == 0.0 && pricing.output == 0.0 {
+        api.pricing = None;
+    }
+}
+
 fn main() -> Result<(), iced::Error> {
     #[cfg(debug_assertions)]
     tracing_subscriber::fmt()
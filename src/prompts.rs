@@ -0,0 +1,38 @@
+use crate::config::Prompt;
+
+const CLIPBOARD: &str = "{{clipboard}}";
+
+/// Values of the variables a system prompt may use
+#[derive(Debug, Clone, Default)]
+pub struct PromptVars {
+    pub language: String,
+    pub clipboard: Option<String>,
+    pub selection: Option<String>,
+}
+
+/// The clipboard is read asynchronously, so only when the prompt asks for it
+pub fn needs_clipboard(text: &str) -> bool {
+    text.contains(CLIPBOARD)
+}
+
+/// Replaces `{{date}}`, `{{time}}`, `{{language}}`, `{{clipboard}}` and `{{selection}}`, other text is kept as is
+pub fn expand(text: &str, vars: &PromptVars) -> String {
+    let now = chrono::Local::now();
+    text.replace("{{date}}", now.format("%Y-%m-%d").to_string().as_str())
+        .replace("{{time}}", now.format("%H:%M").to_string().as_str())
+        .replace("{{language}}", vars.language.as_str())
+        .replace(CLIPBOARD, vars.clipboard.as_deref().unwrap_or_default())
+        .replace("{{selection}}", vars.selection.as_deref().unwrap_or_default())
+}
+
+pub fn find<'a>(prompts: &'a [Prompt], name: &str) -> Option<&'a Prompt> {
+    prompts.iter().find(|p| p.name == name)
+}
+
+/// Name not used by any prompt yet
+pub fn new_name(prompts: &[Prompt]) -> String {
+    (1..)
+        .map(|i| format!("Prompt {}", i))
+        .find(|n| find(prompts, n).is_none())
+        .unwrap_or_default()
+}
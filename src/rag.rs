@@ -0,0 +1,374 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use anyhow::{Result, bail};
+use iced::task::{Sipper, sipper};
+use tracing::{debug, error, info};
+use crate::backend::{check_status, http_client};
+use crate::config::RagConfig;
+
+pub const INDEX_FILE: &str = "rag_index.json";
+pub const EXTENSIONS: &[&str] = &["md", "markdown", "txt", "rst", "org"];
+const DEFAULT_TOP_K: usize = 4;
+/// About 400 tokens, small enough for a few chunks to fit any context
+const CHUNK_CHARS: usize = 1600;
+/// Lines repeated at the beginning of the next chunk, so that no passage is cut in two
+const OVERLAP_LINES: usize = 2;
+/// Texts embedded in one request
+const EMBED_BATCH: usize = 32;
+const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Passage of a document with its embedding
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Chunk {
+    pub path: String,
+    /// Lines of the file, counted from 1
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexedFile {
+    /// Seconds since the epoch, the file is embedded again when it changes
+    modified: u64,
+    chunks: Vec<Chunk>,
+}
+
+/// Embeddings of every document of the folder, stored in one file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Index {
+    pub folder: String,
+    pub model: String,
+    files: BTreeMap<String, IndexedFile>,
+    /// Files that could not be embedded by the last build, they are tried again by the next one
+    #[serde(skip)]
+    pub failed: Vec<String>,
+}
+
+/// Chunk found for a prompt, numbered as the model is told to cite it
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub n: usize,
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub score: f32,
+}
+
+impl Source {
+    /// Markdown link opening the file
+    pub fn link(&self) -> String {
+        let name = Path::new(&self.path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.clone());
+        let url = format!("file://{}", self.path.replace(' ', "%20"));
+        format!("[{}, lines {}-{}]({})", name, self.start_line, self.end_line, url)
+    }
+}
+
+impl Index {
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let s = std::fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(s.as_str())?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.files.values().map(|f| f.chunks.len()).sum()
+    }
+
+    /// The chunks most similar to the query, the best first
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(&Chunk, f32)> {
+        let mut res: Vec<(&Chunk, f32)> = self.files.values()
+            .flat_map(|f| f.chunks.iter())
+            .filter(|c| c.vector.len() == query.len())
+            .map(|c| (c, cosine(&c.vector, query)))
+            .collect();
+        res.sort_by(|a, b| b.1.total_cmp(&a.1));
+        res.truncate(k);
+        res
+    }
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    let mut dot = 0.0;
+    let mut na = 0.0;
+    let mut nb = 0.0;
+    for (x, y) in a.iter().zip(b.iter()) {
+        dot += x * y;
+        na += x * x;
+        nb += y * y;
+    }
+    if na == 0.0 || nb == 0.0 {
+        return 0.0;
+    }
+    dot / (na.sqrt() * nb.sqrt())
+}
+
+/// Splits the text into passages of whole lines, preferably at empty lines or headings
+pub fn chunk_text(text: &str) -> Vec<(usize, usize, String)> {
+    let lines: Vec<&str> = text.lines().collect();
+    let sizes: Vec<usize> = lines.iter().map(|l| l.chars().count()).collect();
+    let mut res = vec![];
+    let mut start = 0;
+    while start < lines.len() {
+        let mut end = start;
+        let mut len = 0;
+        // Last place after which the chunk can end nicely
+        let mut brk = None;
+        while end < lines.len() && (len == 0 || len + sizes[end] < CHUNK_CHARS) {
+            len += sizes[end] + 1;
+            end += 1;
+            let next = lines.get(end).map(|l| l.trim()).unwrap_or_default();
+            if next.is_empty() || next.starts_with('#') {
+                brk = Some(end);
+            }
+        }
+        if end < lines.len() {
+            if let Some(b) = brk.filter(|b| (b - start) * 2 > end - start) {
+                end = b;
+            }
+        }
+        let chunk = lines[start..end].join("\n");
+        if !chunk.trim().is_empty() {
+            res.push((start + 1, end, chunk));
+        }
+        if end >= lines.len() {
+            break;
+        }
+        start = end.saturating_sub(OVERLAP_LINES).max(start + 1);
+    }
+    res
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Embeddings of the texts from an OpenAI compatible `/embeddings` endpoint
+pub async fn embed(client: &reqwest::Client, cfg: &RagConfig, input: &[String]) -> Result<Vec<Vec<f32>>> {
+    let url = format!("{}/embeddings", cfg.url.trim_end_matches('/'));
+    let mut req = client.post(url)
+        .json(&EmbeddingRequest { model: cfg.model.as_str(), input });
+    if !cfg.key.is_empty() {
+        req = req.bearer_auth(cfg.key.as_str());
+    }
+    let res = check_status(req.send().await?).await?;
+    let mut data = res.json::<EmbeddingResponse>().await?.data;
+    if data.len() != input.len() {
+        bail!("Asked for {} embeddings, got {}", input.len(), data.len());
+    }
+    data.sort_by_key(|e| e.index);
+    Ok(data.into_iter().map(|e| e.embedding).collect())
+}
+
+fn documents(dir: &Path, res: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            documents(&path, res)?;
+        } else if path.extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXTENSIONS.contains(&e.to_lowercase().as_str())) {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn modified(path: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Chunks of the file with their embeddings
+async fn embed_file(client: &reqwest::Client, cfg: &RagConfig, key: &str, text: &str) -> Result<Vec<Chunk>> {
+    let parts = chunk_text(text);
+    let mut chunks = vec![];
+    for batch in parts.chunks(EMBED_BATCH) {
+        let texts: Vec<String> = batch.iter().map(|(_, _, t)| t.clone()).collect();
+        let vectors = embed(client, cfg, &texts).await?;
+        for ((start_line, end_line, text), vector) in batch.iter().cloned().zip(vectors) {
+            chunks.push(Chunk { path: key.to_string(), start_line, end_line, text, vector });
+        }
+    }
+    Ok(chunks)
+}
+
+/// Embeds the documents of the folder that changed since the previous index, reports the files done.
+/// Files that cannot be embedded are left out and listed in `failed`, the build fails only when none could be
+pub fn build(cfg: RagConfig, previous: Option<Arc<Index>>) -> impl Sipper<Result<Index>, String> {
+    sipper(async move |mut progress| {
+        if cfg.folder.trim().is_empty() || cfg.url.trim().is_empty() || cfg.model.trim().is_empty() {
+            bail!("Set the folder, the embeddings Url and model first");
+        }
+        let mut paths = vec![];
+        documents(Path::new(&cfg.folder), &mut paths)?;
+        paths.sort();
+        // The vectors of another model cannot be compared with the new ones
+        let mut old = previous
+            .filter(|p| p.model == cfg.model && p.folder == cfg.folder)
+            .map(|p| p.files.clone())
+            .unwrap_or_default();
+        let mut index = Index { folder: cfg.folder.clone(), model: cfg.model.clone(), ..Index::default() };
+        let mut first_error = None;
+        let client = http_client(None);
+        let total = paths.len();
+        for (i, path) in paths.into_iter().enumerate() {
+            progress.send(format!("{}/{} files", i, total)).await;
+            let key = path.canonicalize().unwrap_or(path.clone()).display().to_string();
+            let modified = modified(&path)?;
+            if let Some(f) = old.remove(&key).filter(|f| f.modified == modified) {
+                index.files.insert(key, f);
+                continue;
+            }
+            if std::fs::metadata(&path)?.len() > MAX_FILE_SIZE {
+                info!("Skipping {}, it is too large", key);
+                continue;
+            }
+            let text = match tokio::fs::read_to_string(&path).await {
+                Ok(t) => t,
+                Err(e) => {
+                    error!("Cannot read {}: {}", key, e.to_string());
+                    continue;
+                }
+            };
+            match embed_file(&client, &cfg, key.as_str(), text.as_str()).await {
+                Ok(chunks) => {
+                    debug!("Indexed {}: {} chunks", key, chunks.len());
+                    index.files.insert(key, IndexedFile { modified, chunks });
+                }
+                Err(e) => {
+                    error!("Cannot embed {}: {}", key, e.to_string());
+                    index.failed.push(key);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        progress.send(format!("{}/{} files", total, total)).await;
+        if let Some(e) = first_error.filter(|_| index.files.is_empty()) {
+            return Err(e);
+        }
+        Ok::<Index, anyhow::Error>(index)
+    })
+}
+
+/// Finds the passages of the documents for the prompts of the chat worker
+#[derive(Debug, Clone)]
+pub struct Retriever {
+    pub config: RagConfig,
+    pub index: Arc<Index>,
+    client: reqwest::Client,
+}
+
+impl Retriever {
+    pub fn new(config: RagConfig, index: Arc<Index>) -> Self {
+        Self { config, index, client: http_client(None) }
+    }
+
+    /// The passages for the prompt and the message telling the model about them
+    pub async fn retrieve(&self, prompt: &str) -> Result<(String, Vec<Source>)> {
+        if self.index.model != self.config.model {
+            bail!("The index was built with {}, index the documents again", self.index.model);
+        }
+        if self.index.folder != self.config.folder {
+            bail!("The index was built for {}, index the documents again", self.index.folder);
+        }
+        let query = embed(&self.client, &self.config, &[prompt.to_string()]).await?;
+        let k = self.config.top_k.unwrap_or(DEFAULT_TOP_K);
+        let found = self.index.search(&query[0], k);
+        let mut text = String::from("Answer using the excerpts of the user's documents below when they are relevant. \
+Cite the excerpts you use by their number in square brackets, e.g. [1].\n\n");
+        let mut sources = vec![];
+        for (i, (c, score)) in found.into_iter().enumerate() {
+            text.push_str(format!("[{}] {} (lines {}-{}):\n{}\n\n", i + 1, c.path, c.start_line, c.end_line, c.text).as_str());
+            sources.push(Source {
+                n: i + 1,
+                path: c.path.clone(),
+                start_line: c.start_line,
+                end_line: c.end_line,
+                score,
+            });
+        }
+        Ok((text, sources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_of_directions() {
+        assert!((cosine(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine(&[1.0, -1.0], &[-2.0, 2.0]) + 1.0).abs() < 1e-6);
+        assert_eq!(cosine(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(chunk_text("one\ntwo\n"), vec![(1, 2, "one\ntwo".to_string())]);
+        assert!(chunk_text("").is_empty());
+        assert!(chunk_text("\n  \n").is_empty());
+    }
+
+    #[test]
+    fn chunks_are_counted_in_characters() {
+        // 200 bytes but 100 characters with the end of line
+        let line = "é".repeat(99);
+        let text = vec![line.as_str(); 40].join("\n");
+        let chunks = chunk_text(text.as_str());
+        assert_eq!((chunks[0].0, chunks[0].1), (1, 16));
+        for (_, _, c) in chunks.iter() {
+            assert!(c.chars().count() <= CHUNK_CHARS);
+        }
+        // The next chunk repeats the last lines, the last one ends with the text
+        assert_eq!(chunks[1].0, 16 - OVERLAP_LINES + 1);
+        assert_eq!(chunks.last().unwrap().1, 40);
+    }
+
+    #[test]
+    fn chunks_end_before_headings() {
+        let para = "word ".repeat(19);
+        let mut lines = vec![para.as_str(); 12];
+        lines.push("# Next");
+        lines.extend(vec![para.as_str(); 12]);
+        let chunks = chunk_text(lines.join("\n").as_str());
+        assert_eq!((chunks[0].0, chunks[0].1), (1, 12));
+        assert!(chunks[1].2.contains("# Next"));
+    }
+}
@@ -0,0 +1,392 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use chrono::{DateTime, NaiveDate, Utc};
+use openai::chat::ChatCompletionMessageRole;
+use anyhow::Result;
+use tracing::{debug, error};
+use crate::session::{Session, SessionStore};
+
+pub const INDEX_FILE: &str = "search_index.json";
+/// Characters of a message shown around the first match
+const SNIPPET_CHARS: usize = 160;
+const MAX_HITS: usize = 200;
+
+crate::make_enum!(RoleFilter, [Any, User, Assistant]);
+
+/// Searchable text of a message of the branch shown
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    pos: usize,
+    user: bool,
+    time: Option<DateTime<Utc>>,
+    /// The content with the transcription of the recording it was dictated with
+    text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Doc {
+    title: String,
+    provider: String,
+    updated: DateTime<Utc>,
+    /// Renaming does not change `updated`, the tags tell a renamed session too
+    #[serde(default)]
+    tags: Vec<String>,
+    entries: Vec<Entry>,
+}
+
+/// Inverted index of the stored sessions, updated with every saved session.
+/// Only the branch shown of each session is indexed, so a hit is a message the session opens with
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SearchIndex {
+    docs: BTreeMap<String, Doc>,
+    /// Lowercase words with the session and the position of the messages they are in
+    terms: BTreeMap<String, BTreeSet<(String, usize)>>,
+}
+
+/// What the search is limited to besides the words
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub query: String,
+    pub provider: Option<String>,
+    pub role: RoleFilter,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+}
+
+/// Message found, with the part of its text to show and whether each piece matched
+#[derive(Debug, Clone)]
+pub struct Hit {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub pos: usize,
+    pub user: bool,
+    pub time: DateTime<Utc>,
+    pub snippet: Vec<(String, bool)>,
+}
+
+fn words(s: &str) -> impl Iterator<Item = String> + '_ {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+}
+
+fn entries(session: &Session) -> Vec<Entry> {
+    let mut res = vec![];
+    for (pos, m) in session.messages.iter().enumerate() {
+        let user = match m.role {
+            ChatCompletionMessageRole::User => true,
+            ChatCompletionMessageRole::Assistant => false,
+            _ => continue,
+        };
+        let mut text = m.content.clone().unwrap_or_default();
+        // The prompt may have been edited after it was dictated
+        if let Some(t) = m.transcription.as_ref().filter(|t| !text.contains(t.text.trim())) {
+            text.push_str(format!("\n{}", t.text.trim()).as_str());
+        }
+        if text.trim().is_empty() {
+            continue;
+        }
+        res.push(Entry { pos, user, time: m.time, text });
+    }
+    res
+}
+
+impl SearchIndex {
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let s = std::fs::read_to_string(path)?;
+        Ok(Some(serde_json::from_str(s.as_str())?))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let Some(doc) = self.docs.remove(id) else {
+            return;
+        };
+        for e in doc.entries.iter() {
+            for w in words(e.text.as_str()) {
+                if let Some(postings) = self.terms.get_mut(&w) {
+                    postings.remove(&(id.to_string(), e.pos));
+                    if postings.is_empty() {
+                        self.terms.remove(&w);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Indexes the session again, only its words are touched
+    pub fn update(&mut self, session: &Session) {
+        self.remove(session.id.as_str());
+        if session.is_empty() {
+            return;
+        }
+        let entries = entries(session);
+        for e in entries.iter() {
+            for w in words(e.text.as_str()) {
+                self.terms.entry(w).or_default().insert((session.id.clone(), e.pos));
+            }
+        }
+        self.docs.insert(session.id.clone(), Doc {
+            title: session.title.clone(),
+            provider: session.provider.clone(),
+            updated: session.updated,
+            tags: session.tags.clone(),
+            entries,
+        });
+    }
+
+    /// Brings the index up to date with the stored sessions, true when something changed
+    pub fn sync(&mut self, store: &SessionStore) -> Result<bool> {
+        let list = store.list()?;
+        let mut changed = false;
+        let stored: BTreeSet<&str> = list.iter().map(|s| s.id.as_str()).collect();
+        let gone: Vec<String> = self.docs.keys()
+            .filter(|id| !stored.contains(id.as_str()))
+            .cloned()
+            .collect();
+        for id in gone {
+            self.remove(id.as_str());
+            changed = true;
+        }
+        for info in list.iter() {
+            let doc = self.docs.get(&info.id);
+            if doc.is_some_and(|d| d.updated == info.updated && d.title == info.title && d.tags == info.tags) {
+                continue;
+            }
+            match store.load(&info.id) {
+                Ok(s) => self.update(&s),
+                Err(e) => error!("Cannot index session {}: {}", info.id, e.to_string()),
+            }
+            changed = true;
+        }
+        debug!("Search index: {} sessions, {} words", self.docs.len(), self.terms.len());
+        Ok(changed)
+    }
+
+    /// Names of the chats the sessions were held with
+    pub fn providers(&self) -> Vec<String> {
+        let names: BTreeSet<&String> = self.docs.values().map(|d| &d.provider).collect();
+        names.into_iter().cloned().collect()
+    }
+
+    /// Messages containing every word of the query, a word also matches the longer words it begins, the newest first
+    pub fn search(&self, filter: &Filter) -> Vec<Hit> {
+        let query: Vec<String> = words(filter.query.as_str()).collect();
+        let mut found: Option<BTreeSet<&(String, usize)>> = None;
+        for q in query.iter() {
+            let matches: BTreeSet<&(String, usize)> = self.terms.range(q.clone()..)
+                .take_while(|(w, _)| w.starts_with(q.as_str()))
+                .flat_map(|(_, p)| p.iter())
+                .collect();
+            found = Some(match found {
+                Some(f) => f.intersection(&matches).copied().collect(),
+                None => matches,
+            });
+        }
+        let mut res = vec![];
+        for (id, pos) in found.unwrap_or_default() {
+            let Some(doc) = self.docs.get(id) else {
+                continue;
+            };
+            if filter.provider.as_ref().is_some_and(|p| *p != doc.provider) {
+                continue;
+            }
+            let Some(e) = doc.entries.iter().find(|e| e.pos == *pos) else {
+                continue;
+            };
+            let role_ok = match filter.role {
+                RoleFilter::Any => true,
+                RoleFilter::User => e.user,
+                RoleFilter::Assistant => !e.user,
+            };
+            let time = e.time.unwrap_or(doc.updated);
+            let date = time.date_naive();
+            if !role_ok || filter.from.is_some_and(|d| date < d) || filter.to.is_some_and(|d| date > d) {
+                continue;
+            }
+            res.push(Hit {
+                id: id.clone(),
+                title: doc.title.clone(),
+                provider: doc.provider.clone(),
+                pos: *pos,
+                user: e.user,
+                time,
+                snippet: snippet(e.text.as_str(), &query),
+            });
+        }
+        res.sort_by(|a, b| b.time.cmp(&a.time));
+        res.truncate(MAX_HITS);
+        res
+    }
+}
+
+/// The text around the first match on one line, split into the pieces that matched and the ones that did not
+pub fn snippet(text: &str, query: &[String]) -> Vec<(String, bool)> {
+    let chars: Vec<char> = text.chars()
+        .map(|c| if c.is_whitespace() { ' ' } else { c })
+        .collect();
+    // Words of the text as ranges of characters, marked when they match
+    let mut spans = vec![];
+    let mut start = None;
+    for i in 0..=chars.len() {
+        let alnum = chars.get(i).is_some_and(|c| c.is_alphanumeric());
+        match (start, alnum) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                let w: String = chars[s..i].iter().collect::<String>().to_lowercase();
+                if query.iter().any(|q| w.starts_with(q.as_str())) {
+                    spans.push((s, i));
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    let first = spans.first().map(|s| s.0).unwrap_or_default();
+    let from = first.saturating_sub(SNIPPET_CHARS / 3);
+    let to = (from + SNIPPET_CHARS).min(chars.len());
+    let mut res = vec![];
+    let mut at = from;
+    let push = |res: &mut Vec<(String, bool)>, a: usize, b: usize, hit: bool| {
+        if a < b {
+            res.push((chars[a..b].iter().collect(), hit));
+        }
+    };
+    for (s, e) in spans.into_iter().filter(|(s, _)| *s >= from && *s < to) {
+        push(&mut res, at, s, false);
+        push(&mut res, s, e.min(to), true);
+        at = e.min(to);
+    }
+    push(&mut res, at, to, false);
+    if from > 0 {
+        res.insert(0, ("…".to_string(), false));
+    }
+    if to < chars.len() {
+        res.push(("…".to_string(), false));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::message;
+
+    fn session(id: &str, texts: &[&str]) -> Session {
+        let mut s = Session::new("Local");
+        s.id = id.to_string();
+        s.messages = texts.iter().enumerate()
+            .map(|(i, t)| {
+                let role = if i % 2 == 0 { ChatCompletionMessageRole::User } else { ChatCompletionMessageRole::Assistant };
+                message(role, *t)
+            })
+            .collect();
+        s
+    }
+
+    fn query(q: &str) -> Filter {
+        Filter { query: q.to_string(), ..Filter::default() }
+    }
+
+    fn found(index: &SearchIndex, filter: &Filter) -> Vec<(String, usize)> {
+        let mut res: Vec<(String, usize)> = index.search(filter).into_iter().map(|h| (h.id, h.pos)).collect();
+        res.sort();
+        res
+    }
+
+    fn index() -> SearchIndex {
+        let mut index = SearchIndex::default();
+        index.update(&session("a", &["How do I bake bread?", "Knead the dough, then bake it."]));
+        index.update(&session("b", &["Bread or rice?", "Rice, boiled."]));
+        index
+    }
+
+    #[test]
+    fn every_word_must_match() {
+        let index = index();
+        assert_eq!(found(&index, &query("bread")), vec![("a".to_string(), 0), ("b".to_string(), 0)]);
+        assert_eq!(found(&index, &query("BAKE bread")), vec![("a".to_string(), 0)]);
+        assert_eq!(found(&index, &query("bread boiled")), vec![]);
+        assert_eq!(found(&index, &query("")), vec![]);
+    }
+
+    #[test]
+    fn words_match_by_their_beginning() {
+        let index = index();
+        assert_eq!(found(&index, &query("kne")), vec![("a".to_string(), 1)]);
+        assert_eq!(found(&index, &query("nead")), vec![]);
+    }
+
+    #[test]
+    fn role_filter() {
+        let index = index();
+        let filter = Filter { role: RoleFilter::Assistant, ..query("rice") };
+        assert_eq!(found(&index, &filter), vec![("b".to_string(), 1)]);
+        let filter = Filter { role: RoleFilter::User, ..query("rice") };
+        assert_eq!(found(&index, &filter), vec![("b".to_string(), 0)]);
+    }
+
+    #[test]
+    fn removed_sessions_leave_no_words() {
+        let mut index = index();
+        index.remove("b");
+        assert_eq!(found(&index, &query("bread")), vec![("a".to_string(), 0)]);
+        assert!(!index.terms.contains_key("rice"));
+        assert!(!index.terms.contains_key("boiled"));
+        assert!(index.terms.contains_key("bread"));
+        // Indexing again replaces the words of the session
+        index.update(&session("a", &["Only soup now"]));
+        assert_eq!(found(&index, &query("bread")), vec![]);
+        assert_eq!(found(&index, &query("soup")), vec![("a".to_string(), 0)]);
+    }
+
+    #[test]
+    fn snippet_marks_matches() {
+        let pieces = snippet("Bake the bread\nat 220°C", &["bread".to_string()]);
+        assert_eq!(pieces, vec![
+            ("Bake the ".to_string(), false),
+            ("bread".to_string(), true),
+            (" at 220°C".to_string(), false),
+        ]);
+    }
+
+    #[test]
+    fn snippet_cuts_multibyte_text_at_characters() {
+        let text = format!("{} 日本語のテキスト {}", "é".repeat(300), "ü".repeat(300));
+        let pieces = snippet(text.as_str(), &["日本語".to_string()]);
+        assert_eq!(pieces.first(), Some(&("…".to_string(), false)));
+        assert_eq!(pieces.last(), Some(&("…".to_string(), false)));
+        let hit: Vec<&str> = pieces.iter().filter(|p| p.1).map(|p| p.0.as_str()).collect();
+        assert_eq!(hit, vec!["日本語のテキスト"]);
+        let shown: String = pieces[1..pieces.len() - 1].iter().map(|p| p.0.as_str()).collect();
+        assert_eq!(shown.chars().count(), SNIPPET_CHARS);
+        assert!(shown.starts_with('é') && shown.ends_with('ü'));
+    }
+
+    #[test]
+    fn sync_notices_new_tags() {
+        let dir = std::env::temp_dir().join(format!("search-{}", uuid::Uuid::now_v1(&[1, 2, 3, 4, 5, 6])));
+        let store = SessionStore::new(&dir);
+        let s = session("t", &["hello", "hi"]);
+        store.save(&s).unwrap();
+        let mut index = SearchIndex::default();
+        assert!(index.sync(&store).unwrap());
+        assert!(!index.sync(&store).unwrap());
+        store.rename("t", s.title.as_str(), vec!["greeting".to_string()]).unwrap();
+        assert!(index.sync(&store).unwrap());
+        store.delete("t").unwrap();
+        assert!(index.sync(&store).unwrap());
+        assert!(index.docs.is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
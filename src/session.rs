@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+use openai::chat::ChatCompletionMessageRole;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use anyhow::Result;
+use tracing::{debug, error};
+use crate::usage::UsageTotals;
+use crate::chat::ChatMessage;
+
+const TITLE_LEN: usize = 40;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    /// Not stored once there is a tree, it is made from the path again on load
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<ChatMessage>,
+    #[serde(default)]
+    pub usage: UsageTotals,
+    /// System prompt chosen for this session instead of the default one
+    #[serde(default)]
+    pub prompt: Option<String>,
+    /// Every message of the session with all of its branches, `messages` is the branch shown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tree: Vec<Node>,
+    /// Nodes of the tree making the branch shown
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub path: Vec<usize>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// The title was given by the model or the user, it is not asked for again
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub titled: bool,
+}
+
+/// Message in the conversation tree, messages with the same parent are alternatives to each other
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Node {
+    pub parent: Option<usize>,
+    pub message: ChatMessage,
+}
+
+/// The messages are the same as far as the branches are concerned
+fn same(a: &ChatMessage, b: &ChatMessage) -> bool {
+    a.role == b.role && a.content == b.content && a.tool_call_id == b.tool_call_id
+}
+
+/// Short description of a stored session, used for listing
+#[derive(Clone, Debug)]
+pub struct SessionInfo {
+    pub id: String,
+    pub title: String,
+    pub provider: String,
+    pub updated: DateTime<Utc>,
+    pub tags: Vec<String>,
+}
+
+impl Session {
+    pub fn new(provider: impl Into<String>) -> Self {
+        let now = Utc::now();
+        Self {
+            id: uuid::Uuid::now_v1(&[21, 2, 31, 52, 0, 61]).to_string(),
+            title: String::new(),
+            provider: provider.into(),
+            created: now,
+            updated: now,
+            messages: vec![],
+            usage: UsageTotals::default(),
+            prompt: None,
+            tree: vec![],
+            path: vec![],
+            tags: vec![],
+            titled: false,
+        }
+    }
+
+    /// Builds the tree of a session stored before it had branches, or the branch shown of one stored with its tree only
+    fn ensure_tree(&mut self) {
+        if !self.tree.is_empty() {
+            if self.messages.is_empty() {
+                self.messages = self.path.iter()
+                    .filter_map(|i| self.tree.get(*i))
+                    .map(|n| n.message.clone())
+                    .collect();
+            }
+            return;
+        }
+        if self.messages.is_empty() {
+            return;
+        }
+        self.path.clear();
+        for m in self.messages.clone() {
+            self.append(m);
+        }
+    }
+
+    fn append(&mut self, message: ChatMessage) {
+        let parent = self.path.last().copied();
+        self.tree.push(Node { parent, message });
+        self.path.push(self.tree.len() - 1);
+    }
+
+    fn children(&self, parent: Option<usize>) -> Vec<usize> {
+        (0..self.tree.len())
+            .filter(|i| self.tree[*i].parent == parent)
+            .collect()
+    }
+
+    /// Follows the conversation of the chat worker, from the first message it differs in it makes a new branch
+    pub fn update(&mut self, history: Vec<ChatMessage>) {
+        self.ensure_tree();
+        let keep = self.path.iter()
+            .zip(history.iter())
+            .take_while(|(i, m)| same(&self.tree[**i].message, m))
+            .count();
+        self.path.truncate(keep);
+        for m in history[keep..].iter() {
+            self.append(m.clone());
+        }
+        self.messages = history;
+    }
+
+    /// Cuts the branch shown before the message, what followed stays in the tree
+    pub fn truncate(&mut self, pos: usize) {
+        self.ensure_tree();
+        self.path.truncate(pos);
+        self.messages.truncate(pos);
+    }
+
+    /// Index of the message among its alternatives and their number
+    pub fn siblings(&self, pos: usize) -> (usize, usize) {
+        let Some(node) = self.path.get(pos) else {
+            return (0, 1);
+        };
+        let s = self.children(self.tree[*node].parent);
+        (s.iter().position(|i| i == node).unwrap_or(0), s.len())
+    }
+
+    /// Shows the previous or the next alternative to the message, continued with its latest answers
+    pub fn switch(&mut self, pos: usize, forward: bool) -> bool {
+        let (i, n) = self.siblings(pos);
+        let j = if forward { i + 1 } else { i.wrapping_sub(1) };
+        if j >= n {
+            return false;
+        }
+        let mut node = self.children(self.tree[self.path[pos]].parent)[j];
+        self.path.truncate(pos);
+        loop {
+            self.path.push(node);
+            match self.children(Some(node)).last() {
+                Some(c) => node = *c,
+                None => break,
+            }
+        }
+        self.messages = self.path.iter()
+            .map(|i| self.tree[*i].message.clone())
+            .collect();
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Sets the title from the first user message if there is none yet
+    pub fn default_title(&mut self) {
+        if !self.title.is_empty() {
+            return;
+        }
+        let first = self.messages.iter()
+            .find(|m| m.role == ChatCompletionMessageRole::User)
+            .and_then(|m| m.content.as_ref());
+        if let Some(first) = first {
+            let line = first.lines().next().unwrap_or_default().trim();
+            self.title = if line.chars().count() > TITLE_LEN {
+                format!("{}...", line.chars().take(TITLE_LEN).collect::<String>())
+            } else {
+                line.to_string()
+            };
+        }
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        SessionInfo {
+            id: self.id.clone(),
+            title: self.title.clone(),
+            provider: self.provider.clone(),
+            updated: self.updated,
+            tags: self.tags.clone(),
+        }
+    }
+
+    /// Renders the conversation as markdown for the result pane
+    pub fn transcript(&self) -> String {
+        let mut res = String::new();
+        for m in self.messages.iter() {
+            let who = match m.role {
+                ChatCompletionMessageRole::User => "You",
+                ChatCompletionMessageRole::Assistant => "Assistant",
+                _ => continue,
+            };
+            // Messages only calling tools have no text
+            let content = m.content.as_deref().unwrap_or_default();
+            if content.is_empty() && m.images.is_empty() && m.files.is_empty() {
+                continue;
+            }
+            res.push_str(format!("### {}\n\n", who).as_str());
+            if !m.images.is_empty() {
+                res.push_str(format!("*[{} image(s)]*\n\n", m.images.len()).as_str());
+            }
+            if !m.files.is_empty() {
+                let names: Vec<&str> = m.files.iter().map(|f| f.name.as_str()).collect();
+                res.push_str(format!("*[files: {}]*\n\n", names.join(", ")).as_str());
+            }
+            res.push_str(format!("{}\n\n", content).as_str());
+            if m.truncated {
+                res.push_str("*[truncated]*\n\n");
+            }
+        }
+        res
+    }
+
+    /// The first answer came and the session has no title from the model or the user yet
+    pub fn needs_title(&self) -> bool {
+        !self.titled && self.messages.iter().any(|m| {
+            m.role == ChatCompletionMessageRole::Assistant && m.content.as_deref().is_some_and(|c| !c.is_empty())
+        })
+    }
+
+    pub fn last_answer(&self) -> Option<&str> {
+        self.messages.iter()
+            .rev()
+            .filter(|m| m.role == ChatCompletionMessageRole::Assistant)
+            .filter_map(|m| m.content.as_deref())
+            .find(|c| !c.is_empty())
+    }
+}
+
+/// Stores every session as a separate JSON file in one directory
+#[derive(Clone, Debug)]
+pub struct SessionStore {
+    dir: PathBuf,
+}
+
+impl SessionStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Lists stored sessions, the most recently updated first
+    pub fn list(&self) -> Result<Vec<SessionInfo>> {
+        let mut res = vec![];
+        if !self.dir.exists() {
+            return Ok(res);
+        }
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            match std::fs::read_to_string(&path).map(|s| serde_json::from_str::<Session>(s.as_str())) {
+                Ok(Ok(s)) => res.push(s.info()),
+                Ok(Err(e)) => error!("Invalid session file {:?}: {}", path, e.to_string()),
+                Err(e) => error!("Cannot read session file {:?}: {}", path, e.to_string()),
+            }
+        }
+        res.sort_by(|a, b| b.updated.cmp(&a.updated));
+        Ok(res)
+    }
+
+    pub fn load(&self, id: &str) -> Result<Session> {
+        let s = std::fs::read_to_string(self.path(id))?;
+        let mut session: Session = serde_json::from_str(s.as_str())?;
+        session.ensure_tree();
+        Ok(session)
+    }
+
+    pub fn save(&self, session: &Session) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        // The branch shown is already in the tree
+        let s = if session.tree.is_empty() {
+            serde_json::to_string_pretty(session)?
+        } else {
+            serde_json::to_string_pretty(&Session { messages: vec![], ..session.clone() })?
+        };
+        std::fs::write(self.path(&session.id), s)?;
+        debug!("Session {} saved", session.id);
+        Ok(())
+    }
+
+    pub fn rename(&self, id: &str, title: &str, tags: Vec<String>) -> Result<Session> {
+        let mut session = self.load(id)?;
+        session.title = title.to_string();
+        session.tags = tags;
+        session.titled = true;
+        self.save(&session)?;
+        Ok(session)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<()> {
+        std::fs::remove_file(self.path(id))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::message;
+
+    fn user(text: &str) -> ChatMessage {
+        message(ChatCompletionMessageRole::User, text)
+    }
+
+    fn assistant(text: &str) -> ChatMessage {
+        message(ChatCompletionMessageRole::Assistant, text)
+    }
+
+    fn texts(messages: &[ChatMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.content.as_deref().unwrap_or_default()).collect()
+    }
+
+    fn temp_store(name: &str) -> SessionStore {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, uuid::Uuid::now_v1(&[1, 2, 3, 4, 5, 6])));
+        SessionStore::new(dir)
+    }
+
+    /// Two exchanges, the second prompt edited and answered again
+    fn edited() -> Session {
+        let mut s = Session::new("p");
+        s.update(vec![user("q1"), assistant("a1"), user("q2"), assistant("a2")]);
+        s.truncate(2);
+        s.update(vec![user("q1"), assistant("a1"), user("q2 edited"), assistant("a2 again")]);
+        s
+    }
+
+    #[test]
+    fn edit_makes_a_sibling() {
+        let s = edited();
+        assert_eq!(s.tree.len(), 6);
+        assert_eq!(texts(&s.messages), vec!["q1", "a1", "q2 edited", "a2 again"]);
+        assert_eq!(s.siblings(2), (1, 2));
+        assert_eq!(s.siblings(1), (0, 1));
+    }
+
+    #[test]
+    fn switch_within_bounds() {
+        let mut s = edited();
+        assert!(!s.switch(2, true));
+        assert!(s.switch(2, false));
+        assert_eq!(texts(&s.messages), vec!["q1", "a1", "q2", "a2"]);
+        assert_eq!(s.siblings(2), (0, 2));
+        assert!(!s.switch(2, false));
+        assert!(s.switch(2, true));
+        assert_eq!(texts(&s.messages), vec!["q1", "a1", "q2 edited", "a2 again"]);
+        // Past the end of the branch shown
+        assert!(!s.switch(10, true));
+        assert!(!s.switch(10, false));
+    }
+
+    #[test]
+    fn switch_follows_the_latest_answer() {
+        let mut s = Session::new("p");
+        s.update(vec![user("q1"), assistant("a1")]);
+        s.truncate(1);
+        s.update(vec![user("q1"), assistant("a1 regenerated")]);
+        s.truncate(0);
+        s.update(vec![user("other"), assistant("b1")]);
+        assert!(s.switch(0, false));
+        assert_eq!(texts(&s.messages), vec!["q1", "a1 regenerated"]);
+    }
+
+    #[test]
+    fn truncate_keeps_the_rest_in_the_tree() {
+        let mut s = Session::new("p");
+        s.update(vec![user("q1"), assistant("a1"), user("q2"), assistant("a2")]);
+        s.truncate(1);
+        assert_eq!(texts(&s.messages), vec!["q1"]);
+        assert_eq!(s.tree.len(), 4);
+        s.update(vec![user("q1"), assistant("a1 again")]);
+        assert!(s.switch(1, false));
+        assert_eq!(texts(&s.messages), vec!["q1", "a1", "q2", "a2"]);
+    }
+
+    #[test]
+    fn flat_sessions_get_a_tree() {
+        let store = temp_store("sessions-flat");
+        let mut s = Session::new("p");
+        s.messages = vec![user("q1"), assistant("a1")];
+        // Written the way versions without branches did
+        std::fs::create_dir_all(&store.dir).unwrap();
+        std::fs::write(store.path(&s.id), serde_json::to_string(&s).unwrap()).unwrap();
+
+        let loaded = store.load(&s.id).unwrap();
+        assert_eq!(loaded.tree.len(), 2);
+        assert_eq!(loaded.path, vec![0, 1]);
+        assert_eq!(texts(&loaded.messages), vec!["q1", "a1"]);
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let store = temp_store("sessions-saved");
+        let mut s = edited();
+        assert!(s.switch(2, false));
+        store.save(&s).unwrap();
+        let stored: serde_json::Value = serde_json::from_str(std::fs::read_to_string(store.path(&s.id)).unwrap().as_str()).unwrap();
+        assert!(stored.get("messages").is_none());
+
+        let loaded = store.load(&s.id).unwrap();
+        assert_eq!(loaded.path, s.path);
+        assert_eq!(texts(&loaded.messages), texts(&s.messages));
+        assert_eq!(loaded.tree.len(), s.tree.len());
+        std::fs::remove_dir_all(&store.dir).unwrap();
+    }
+}
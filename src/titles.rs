@@ -0,0 +1,89 @@
+use serde::Deserialize;
+use openai::chat::ChatCompletionMessageRole;
+use crate::chat::{self, ChatError, ChatMessage};
+use crate::config::AiApi;
+use crate::usage::ExchangeStats;
+
+/// Characters of each message the title is made from
+const EXCERPT_CHARS: usize = 2000;
+const MAX_TAGS: usize = 5;
+const TITLE_LEN: usize = 60;
+
+const INSTRUCTION: &str = "Name the conversation below. Answer only with JSON like \
+{\"title\": \"Short title\", \"tags\": [\"tag\", \"another tag\"]}. \
+The title has at most six words, in the language of the conversation. \
+Give up to five short lowercase tags for its topics.";
+
+/// Name and topics suggested for a session
+#[derive(Debug, Clone)]
+pub struct Titled {
+    pub title: String,
+    pub tags: Vec<String>,
+    pub stats: ExchangeStats,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    title: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn excerpt(s: &str) -> String {
+    if s.chars().count() > EXCERPT_CHARS {
+        format!("{}...", s.chars().take(EXCERPT_CHARS).collect::<String>())
+    } else {
+        s.to_string()
+    }
+}
+
+/// The title and tags from the answer, its first line when it is not the JSON asked for
+fn parse(answer: &str) -> (String, Vec<String>) {
+    let json = answer.find('{')
+        .zip(answer.rfind('}'))
+        .filter(|(a, b)| a < b)
+        .and_then(|(a, b)| serde_json::from_str::<Answer>(&answer[a..=b]).ok());
+    let (title, tags) = match json {
+        Some(a) => (a.title, a.tags),
+        None => (answer.lines().find(|l| !l.trim().is_empty()).unwrap_or_default().to_string(), vec![]),
+    };
+    let title = title.trim().trim_matches(|c| c == '"' || c == '#' || c == '*').trim();
+    let title = if title.chars().count() > TITLE_LEN {
+        format!("{}...", title.chars().take(TITLE_LEN).collect::<String>())
+    } else {
+        title.to_string()
+    };
+    let mut res: Vec<String> = vec![];
+    for t in tags {
+        let t = t.trim().trim_start_matches('#').to_lowercase();
+        if !t.is_empty() && !res.contains(&t) {
+            res.push(t);
+        }
+    }
+    res.truncate(MAX_TAGS);
+    (title, res)
+}
+
+/// Asks the chat for a title and tags from the first exchange of the messages, apart from the chat worker
+pub async fn suggest(api: AiApi, messages: Vec<ChatMessage>) -> Result<Titled, ChatError> {
+    let mut conversation = String::new();
+    let exchange = messages.iter()
+        .filter(|m| m.role == ChatCompletionMessageRole::User || m.role == ChatCompletionMessageRole::Assistant)
+        .filter(|m| m.content.as_deref().is_some_and(|c| !c.is_empty()))
+        .take(2);
+    for m in exchange {
+        let who = if m.role == ChatCompletionMessageRole::User { "User" } else { "Assistant" };
+        conversation.push_str(format!("{}: {}\n\n", who, excerpt(m.content.as_deref().unwrap_or_default())).as_str());
+    }
+    let request = vec![
+        chat::message(ChatCompletionMessageRole::System, INSTRUCTION),
+        chat::message(ChatCompletionMessageRole::User, conversation),
+    ];
+
+    let (answer, stats) = chat::complete(&api, &request).await?;
+    let (title, tags) = parse(answer.as_str());
+    if title.is_empty() {
+        return Err(ChatError::Other("The chat gave no title".to_string()));
+    }
+    Ok(Titled { title, tags, stats })
+}
@@ -0,0 +1,429 @@
+use serde_json::{json, Value};
+use openai::chat::{ToolCall as ApiToolCall, ToolCallFunction};
+use anyhow::{Result, anyhow, bail};
+use std::path::Path;
+
+const MAX_FILE_SIZE: u64 = 256 * 1024;
+const MAX_DIR_ENTRIES: usize = 500;
+/// Parentheses, functions and signs nested in each other, the expression comes from the model
+const MAX_DEPTH: usize = 64;
+
+/// Function call requested by the model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub arguments: String,
+}
+
+impl ToolCall {
+    pub fn to_api(&self) -> ApiToolCall {
+        ApiToolCall {
+            id: self.id.clone(),
+            r#type: "function".to_string(),
+            function: ToolCallFunction {
+                name: self.name.clone(),
+                arguments: self.arguments.clone(),
+            },
+        }
+    }
+
+    pub fn from_api(c: &ApiToolCall) -> Self {
+        Self {
+            id: c.id.clone(),
+            name: c.function.name.clone(),
+            arguments: c.function.arguments.clone(),
+        }
+    }
+}
+
+/// Tool as declared to the model
+#[derive(Debug, Clone)]
+pub struct ToolSpec {
+    pub name: String,
+    pub description: String,
+    /// JSON schema of the arguments
+    pub parameters: Value,
+}
+
+impl ToolSpec {
+    /// The form used by OpenAI and Ollama
+    pub fn to_function(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.name,
+                "description": self.description,
+                "parameters": self.parameters,
+            }
+        })
+    }
+}
+
+pub trait Tool: Send + Sync {
+    fn spec(&self) -> ToolSpec;
+    fn call(&self, args: &Value) -> Result<String>;
+}
+
+/// Tools the models are allowed to use
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn builtin() -> Self {
+        Self {
+            tools: vec![
+                Box::new(ReadFile),
+                Box::new(ListDir),
+                Box::new(DateTime),
+                Box::new(Calculate),
+            ],
+        }
+    }
+
+    pub fn specs(&self) -> Vec<ToolSpec> {
+        self.tools.iter().map(|t| t.spec()).collect()
+    }
+
+    pub fn call(&self, call: &ToolCall) -> Result<String> {
+        let tool = self.tools.iter()
+            .find(|t| t.spec().name == call.name)
+            .ok_or_else(|| anyhow!("Unknown tool: {}", call.name))?;
+        let args: Value = if call.arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(call.arguments.as_str())?
+        };
+        tool.call(&args)
+    }
+}
+
+fn str_arg<'a>(args: &'a Value, name: &str) -> Result<&'a str> {
+    args.get(name)
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| anyhow!("Missing argument: {}", name))
+}
+
+struct ReadFile;
+
+impl Tool for ReadFile {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "read_file".to_string(),
+            description: "Reads a text file from the user's computer".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path of the file" }
+                },
+                "required": ["path"]
+            }),
+        }
+    }
+
+    fn call(&self, args: &Value) -> Result<String> {
+        let path = str_arg(args, "path")?;
+        let size = std::fs::metadata(path)?.len();
+        if size > MAX_FILE_SIZE {
+            bail!("File is too large: {} bytes, the limit is {}", size, MAX_FILE_SIZE);
+        }
+        Ok(std::fs::read_to_string(path)?)
+    }
+}
+
+struct ListDir;
+
+impl Tool for ListDir {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "list_directory".to_string(),
+            description: "Lists the entries of a directory on the user's computer, directories end with /".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "path": { "type": "string", "description": "Path of the directory" }
+                },
+                "required": ["path"]
+            }),
+        }
+    }
+
+    fn call(&self, args: &Value) -> Result<String> {
+        let path = str_arg(args, "path")?;
+        let mut entries = vec![];
+        for entry in std::fs::read_dir(Path::new(path))? {
+            let entry = entry?;
+            let mut name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() {
+                name.push('/');
+            }
+            entries.push(name);
+        }
+        entries.sort();
+        let total = entries.len();
+        entries.truncate(MAX_DIR_ENTRIES);
+        if total > MAX_DIR_ENTRIES {
+            entries.push(format!("... and {} more", total - MAX_DIR_ENTRIES));
+        }
+        Ok(entries.join("\n"))
+    }
+}
+
+struct DateTime;
+
+impl Tool for DateTime {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "current_datetime".to_string(),
+            description: "Returns the current local date, time and time zone offset".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        }
+    }
+
+    fn call(&self, _args: &Value) -> Result<String> {
+        Ok(chrono::Local::now().format("%A, %Y-%m-%d %H:%M:%S %:z").to_string())
+    }
+}
+
+struct Calculate;
+
+impl Tool for Calculate {
+    fn spec(&self) -> ToolSpec {
+        ToolSpec {
+            name: "calculate".to_string(),
+            description: "Evaluates an arithmetic expression with + - * / % ^, parentheses and sqrt, abs, ln, log, sin, cos, tan".to_string(),
+            parameters: json!({
+                "type": "object",
+                "properties": {
+                    "expression": { "type": "string", "description": "Expression, e.g. (2 + 3) * 4 ^ 2" }
+                },
+                "required": ["expression"]
+            }),
+        }
+    }
+
+    fn call(&self, args: &Value) -> Result<String> {
+        let expr = str_arg(args, "expression")?;
+        Ok(eval(expr)?.to_string())
+    }
+}
+
+/// Evaluates an arithmetic expression
+pub fn eval(expr: &str) -> Result<f64> {
+    let mut p = Parser { s: expr.as_bytes(), pos: 0, depth: 0 };
+    let v = p.expr()?;
+    p.skip_ws();
+    if p.pos < p.s.len() {
+        bail!("Unexpected '{}' at {}", p.s[p.pos] as char, p.pos);
+    }
+    Ok(v)
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_ws(&mut self) {
+        while self.pos < self.s.len() && self.s[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.s.get(self.pos).copied()
+    }
+
+    /// Runs a rule that recurses, too deep nesting would overflow the stack
+    fn nested(&mut self, rule: impl FnOnce(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth >= MAX_DEPTH {
+            bail!("The expression is nested deeper than {} levels", MAX_DEPTH);
+        }
+        self.depth += 1;
+        let v = rule(self);
+        self.depth -= 1;
+        v
+    }
+
+    // expr := term (('+' | '-') term)*
+    fn expr(&mut self) -> Result<f64> {
+        let mut v = self.term()?;
+        while let Some(c) = self.peek() {
+            match c {
+                b'+' => { self.pos += 1; v += self.term()?; }
+                b'-' => { self.pos += 1; v -= self.term()?; }
+                _ => break,
+            }
+        }
+        Ok(v)
+    }
+
+    // term := unary (('*' | '/' | '%') unary)*
+    fn term(&mut self) -> Result<f64> {
+        let mut v = self.unary()?;
+        while let Some(c) = self.peek() {
+            match c {
+                b'*' => { self.pos += 1; v *= self.unary()?; }
+                b'/' | b'%' => {
+                    self.pos += 1;
+                    let d = self.unary()?;
+                    if d == 0.0 {
+                        bail!("Division by zero");
+                    }
+                    v = if c == b'/' { v / d } else { v % d };
+                }
+                _ => break,
+            }
+        }
+        Ok(v)
+    }
+
+    // unary := '-' unary | power, so that -2^2 is -(2^2)
+    fn unary(&mut self) -> Result<f64> {
+        self.nested(|p| match p.peek() {
+            Some(b'-') => { p.pos += 1; Ok(-p.unary()?) }
+            Some(b'+') => { p.pos += 1; p.unary() }
+            _ => p.power(),
+        })
+    }
+
+    // power := atom ('^' unary)?, right associative
+    fn power(&mut self) -> Result<f64> {
+        let base = self.atom()?;
+        if self.peek() == Some(b'^') {
+            self.pos += 1;
+            return Ok(base.powf(self.unary()?));
+        }
+        Ok(base)
+    }
+
+    // atom := number | '(' expr ')' | name '(' expr ')' | constant
+    fn atom(&mut self) -> Result<f64> {
+        self.nested(Self::value)
+    }
+
+    fn value(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some(b'(') => {
+                self.pos += 1;
+                let v = self.expr()?;
+                if self.peek() != Some(b')') {
+                    bail!("Missing ')' at {}", self.pos);
+                }
+                self.pos += 1;
+                Ok(v)
+            }
+            Some(c) if c.is_ascii_digit() || c == b'.' => {
+                let start = self.pos;
+                while self.pos < self.s.len() && (self.s[self.pos].is_ascii_digit() || self.s[self.pos] == b'.') {
+                    self.pos += 1;
+                }
+                let n = std::str::from_utf8(&self.s[start..self.pos])?;
+                Ok(n.parse()?)
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.pos;
+                while self.pos < self.s.len() && self.s[self.pos].is_ascii_alphabetic() {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.s[start..self.pos])?.to_lowercase();
+                match name.as_str() {
+                    "pi" => return Ok(std::f64::consts::PI),
+                    "e" => return Ok(std::f64::consts::E),
+                    _ => {}
+                }
+                let arg = self.atom()?;
+                match name.as_str() {
+                    "sqrt" => Ok(arg.sqrt()),
+                    "abs" => Ok(arg.abs()),
+                    "ln" => Ok(arg.ln()),
+                    "log" => Ok(arg.log10()),
+                    "sin" => Ok(arg.sin()),
+                    "cos" => Ok(arg.cos()),
+                    "tan" => Ok(arg.tan()),
+                    _ => bail!("Unknown function: {}", name),
+                }
+            }
+            Some(c) => bail!("Unexpected '{}' at {}", c as char, self.pos),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(expr: &str, expected: f64) {
+        let v = eval(expr).unwrap();
+        assert!((v - expected).abs() < 1e-9, "{} gave {}, expected {}", expr, v, expected);
+    }
+
+    #[test]
+    fn precedence() {
+        close("2 + 3 * 4", 14.0);
+        close("(2 + 3) * 4", 20.0);
+        close("10 - 4 - 3", 3.0);
+        close("7 % 4 * 2", 6.0);
+        close("-2^2", -4.0);
+        close("2^-1", 0.5);
+        close("-(2)^2", -4.0);
+        close("(-2)^2", 4.0);
+        close("sqrt(16) + abs(-3)", 7.0);
+        close("2 * pi", 2.0 * std::f64::consts::PI);
+    }
+
+    #[test]
+    fn power_is_right_associative() {
+        close("2^3^2", 512.0);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert!(eval("1 / 0").is_err());
+        assert!(eval("1 % (2 - 2)").is_err());
+    }
+
+    #[test]
+    fn garbage() {
+        for expr in ["", "2 +", "(1 + 2", "1 2", "foo(1)", "2 $ 3", "1..2"] {
+            assert!(eval(expr).is_err(), "{} was accepted", expr);
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let deep = format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000));
+        assert!(eval(deep.as_str()).is_err());
+        assert!(eval("-".repeat(10_000).as_str()).is_err());
+        assert!(eval("sqrt ".repeat(10_000).as_str()).is_err());
+        close(format!("{}1{}", "(".repeat(20), ")".repeat(20)).as_str(), 1.0);
+    }
+
+    fn call(name: &str, args: Value) -> Result<String> {
+        let call = ToolCall { id: "1".to_string(), name: name.to_string(), arguments: args.to_string() };
+        ToolRegistry::builtin().call(&call)
+    }
+
+    #[test]
+    fn files() {
+        let dir = std::env::temp_dir().join(format!("tools-test-{}", uuid::Uuid::now_v1(&[1, 2, 3, 4, 5, 6])));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("b.txt"), "hello").unwrap();
+        std::fs::write(dir.join("big.txt"), vec![b'x'; MAX_FILE_SIZE as usize + 1]).unwrap();
+        let path = |name: &str| dir.join(name).display().to_string();
+
+        assert_eq!(call("read_file", json!({ "path": path("b.txt") })).unwrap(), "hello");
+        assert!(call("read_file", json!({ "path": path("big.txt") })).is_err());
+        assert!(call("read_file", json!({ "path": path("missing.txt") })).is_err());
+        assert!(call("read_file", json!({})).is_err());
+        assert_eq!(call("list_directory", json!({ "path": path("") })).unwrap(), "b.txt\nbig.txt\nsub/");
+        assert!(call("no_such_tool", json!({})).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,20 +2,60 @@ use whisper_rs::{WhisperContext, WhisperContextParameters, FullParams, SamplingS
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
 
-pub async fn au_to_text(au: Arc<RwLock<Vec<f32>>>, lang: &str, path_to_model: &str) -> Result<String> {
-    let au = au.read().await;
-    let ctx = WhisperContext::new_with_params(
-        path_to_model,
-	WhisperContextParameters::default())?;
+/// Text recognised in a recording, stored with the prompt it was used for
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transcription {
+    pub text: String,
+    /// The recording as a WAV file, when recordings are kept
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<String>,
+    /// The text in the language it was translated to, sent instead of the text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub translation: Option<String>,
+}
+
+/// Writes the samples as a mono 16 bit WAV file
+pub fn save_wav(path: &Path, samples: &[f32], sample_rate: u32) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let data_len = (samples.len() * 2) as u32;
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    f.write_all(b"RIFF")?;
+    f.write_all(&(36 + data_len).to_le_bytes())?;
+    f.write_all(b"WAVEfmt ")?;
+    f.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel
+    f.write_all(&1u16.to_le_bytes())?;
+    f.write_all(&1u16.to_le_bytes())?;
+    f.write_all(&sample_rate.to_le_bytes())?;
+    f.write_all(&(sample_rate * 2).to_le_bytes())?;
+    f.write_all(&2u16.to_le_bytes())?;
+    f.write_all(&16u16.to_le_bytes())?;
+    f.write_all(b"data")?;
+    f.write_all(&data_len.to_le_bytes())?;
+    for s in samples {
+        let v = (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        f.write_all(&v.to_le_bytes())?;
+    }
+    f.flush()?;
+    Ok(())
+}
+
+fn full(ctx: &WhisperContext, samples: &[f32], lang: &str, translate: bool) -> Result<String> {
     let mut res = String::new();
 
     let mut params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
     let lang = lang.to_string().to_lowercase();
     params.set_language(Some(lang.as_str()));
+    params.set_translate(translate);
     let mut state = ctx.create_state()?;
 
-    let _r = state.full(params, au.as_slice())?;
+    let _r = state.full(params, samples)?;
     let num_segments = state.full_n_segments()?;
     for i in 0..num_segments {
         let segment = state.full_get_segment_text(i)?;
@@ -25,4 +65,11 @@ pub async fn au_to_text(au: Arc<RwLock<Vec<f32>>>, lang: &str, path_to_model: &s
     Ok(res)
 }
 
-
+/// The speech as text, translated to English by Whisper itself when asked, made without a chat
+pub async fn au_to_text(au: Arc<RwLock<Vec<f32>>>, lang: &str, path_to_model: &str, translate: bool) -> Result<String> {
+    let au = au.read().await;
+    let ctx = WhisperContext::new_with_params(
+        path_to_model,
+	WhisperContextParameters::default())?;
+    full(&ctx, au.as_slice(), lang, translate)
+}
@@ -0,0 +1,31 @@
+use openai::chat::ChatCompletionMessageRole;
+use crate::chat::{self, ChatError};
+use crate::config::AiApi;
+use crate::usage::ExchangeStats;
+
+/// Text of a transcription in the target language
+#[derive(Debug, Clone)]
+pub struct Translated {
+    pub text: String,
+    pub stats: ExchangeStats,
+}
+
+fn instruction(from: &str, to: &str) -> String {
+    format!("Translate the text you are given from {} to {}. \
+It was dictated, so correct the words that were obviously misheard. \
+Keep its meaning and tone, and answer with the translation only.", from, to)
+}
+
+/// Asks the chat for the translation, apart from the chat worker
+pub async fn translate(api: AiApi, text: String, from: &str, to: &str) -> Result<Translated, ChatError> {
+    let request = vec![
+        chat::message(ChatCompletionMessageRole::System, instruction(from, to)),
+        chat::message(ChatCompletionMessageRole::User, text),
+    ];
+    let (answer, stats) = chat::complete(&api, &request).await?;
+    let text = answer.trim().to_string();
+    if text.is_empty() {
+        return Err(ChatError::Other("The chat gave no translation".to_string()));
+    }
+    Ok(Translated { text, stats })
+}
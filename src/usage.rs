@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use anyhow::Result;
+use crate::config::Pricing;
+
+/// Tokens used by one request as reported by the provider
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Measurements of one answer
+#[derive(Clone, Debug)]
+pub struct ExchangeStats {
+    pub provider: String,
+    pub model: String,
+    pub usage: Usage,
+    /// The provider did not report usage, the tokens are counted approximately
+    pub estimated: bool,
+    pub ttft_ms: Option<u64>,
+    pub duration_ms: u64,
+    pub tokens_per_sec: Option<f32>,
+    pub cost: f64,
+}
+
+impl ExchangeStats {
+    pub fn summary(&self) -> String {
+        let approx = if self.estimated { "~" } else { "" };
+        let mut res = format!("{}{} in / {}{} out",
+            approx, self.usage.prompt_tokens, approx, self.usage.completion_tokens);
+        if let Some(ttft) = self.ttft_ms {
+            res.push_str(format!(", first token {:.2}s", ttft as f64 / 1000.0).as_str());
+        }
+        if let Some(tps) = self.tokens_per_sec {
+            res.push_str(format!(", {:.1} tok/s", tps).as_str());
+        }
+        res
+    }
+}
+
+pub fn cost(pricing: Option<&Pricing>, usage: &Usage) -> f64 {
+    match pricing {
+        Some(p) => (usage.prompt_tokens as f64 * p.input + usage.completion_tokens as f64 * p.output) / 1_000_000.0,
+        None => 0.0,
+    }
+}
+
+/// Running totals of a session or a provider
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageTotals {
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost: f64,
+    #[serde(default)]
+    pub ttft_ms: u64,
+    #[serde(default)]
+    pub ttft_samples: u64,
+    #[serde(default)]
+    pub tokens_per_sec: f64,
+    #[serde(default)]
+    pub tps_samples: u64,
+}
+
+impl UsageTotals {
+    pub fn add(&mut self, s: &ExchangeStats) {
+        self.requests += 1;
+        self.prompt_tokens += s.usage.prompt_tokens;
+        self.completion_tokens += s.usage.completion_tokens;
+        self.cost += s.cost;
+        if let Some(ttft) = s.ttft_ms {
+            self.ttft_ms += ttft;
+            self.ttft_samples += 1;
+        }
+        if let Some(tps) = s.tokens_per_sec {
+            self.tokens_per_sec += tps as f64;
+            self.tps_samples += 1;
+        }
+    }
+
+    pub fn avg_ttft_ms(&self) -> Option<u64> {
+        (self.ttft_samples > 0).then(|| self.ttft_ms / self.ttft_samples)
+    }
+
+    pub fn avg_tokens_per_sec(&self) -> Option<f64> {
+        (self.tps_samples > 0).then(|| self.tokens_per_sec / self.tps_samples as f64)
+    }
+
+    pub fn summary(&self) -> String {
+        format!("{} requests, {} in / {} out, ${:.4}",
+            self.requests, self.prompt_tokens, self.completion_tokens, self.cost)
+    }
+}
+
+/// Cumulative usage of every provider, kept in one file
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct UsageReport {
+    pub providers: BTreeMap<String, UsageTotals>,
+}
+
+impl UsageReport {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let s = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(s.as_str())?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn add(&mut self, s: &ExchangeStats) {
+        self.providers.entry(s.provider.clone())
+            .or_default()
+            .add(s);
+    }
+
+    pub fn total(&self) -> UsageTotals {
+        let mut res = UsageTotals::default();
+        for t in self.providers.values() {
+            res.requests += t.requests;
+            res.prompt_tokens += t.prompt_tokens;
+            res.completion_tokens += t.completion_tokens;
+            res.cost += t.cost;
+        }
+        res
+    }
+}
@@ -72,3 +72,22 @@ pub fn substring_between<'a>(s: &'a str, delimiter: &str) -> Option<&'a str> {
 
     Some(&rest[..end])
 }
+
+/// Approximate token count, close to BPE tokenizers without knowing their vocabulary.
+/// Words take a token per four characters, punctuation and non-latin characters a token each.
+pub fn estimate_tokens(s: &str) -> u64 {
+    let mut tokens = 0;
+    let mut word = 0;
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() {
+            word += 1;
+            continue;
+        }
+        tokens += (word as u64).div_ceil(4);
+        word = 0;
+        if !c.is_whitespace() {
+            tokens += 1;
+        }
+    }
+    tokens + (word as u64).div_ceil(4)
+}